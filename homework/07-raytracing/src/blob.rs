@@ -0,0 +1,174 @@
+use crate::intersections::Crossing;
+use crate::models::AABB;
+use crate::raytracer::Ray;
+use crate::solvers::solve_quadratic;
+use crate::utils::orthonormal_basis;
+use nalgebra::{Point3, Vector2, Vector3};
+use std::ops::Sub;
+
+// samples taken between two consecutive points where the ray enters or leaves a ball, to look
+// for places where the field crosses the threshold
+const SAMPLES_PER_SEGMENT: usize = 16;
+const BISECTION_STEPS: usize = 48;
+
+// A center of a blob, adding `weight` to the field at its center and nothing beyond `radius`
+#[derive(Debug, Clone, Copy)]
+pub struct Ball {
+    pub center: Point3<f64>,
+    pub radius: f64,
+    pub weight: f64,
+}
+
+impl Ball {
+    // 1 - (d / r)^2 inside of the ball, or none outside of it
+    fn falloff(&self, p: &Point3<f64>) -> Option<f64> {
+        let s = 1.0 - p.sub(self.center).magnitude_squared() / (self.radius * self.radius);
+        match s > 0.0 {
+            true => Some(s),
+            false => None,
+        }
+    }
+}
+
+// Metaballs: the surface where the summed field of the balls equals the threshold. Each ball
+// contributes w (1 - (d / r)^2)^3, which falls smoothly to zero at its radius.
+#[derive(Debug, Clone)]
+pub struct Blob {
+    pub threshold: f64,
+    pub balls: Vec<Ball>,
+}
+
+impl Blob {
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            balls: vec![],
+        }
+    }
+
+    pub fn field(&self, p: &Point3<f64>) -> f64 {
+        self.balls
+            .iter()
+            .filter_map(|b| b.falloff(p).map(|s| b.weight * s.powi(3)))
+            .sum()
+    }
+
+    pub fn gradient(&self, p: &Point3<f64>) -> Vector3<f64> {
+        self.balls
+            .iter()
+            .filter_map(|b| {
+                let s = b.falloff(p)?;
+                let scale = -6.0 * b.weight * s * s / (b.radius * b.radius);
+                Some(p.sub(b.center).scale(scale))
+            })
+            .sum()
+    }
+
+    // the blob lies inside the union of the balls, as the field is zero everywhere else
+    pub fn aabb(&self) -> AABB {
+        self.balls
+            .iter()
+            .map(|b| {
+                let r = Vector3::new(b.radius, b.radius, b.radius);
+                AABB::new(b.center - r, b.center + r)
+            })
+            .reduce(|a, b| a.union(&b))
+            .unwrap_or(AABB::new(Point3::origin(), Point3::origin()))
+    }
+
+    fn crossing(&self, ray: &Ray, t: f64) -> Crossing {
+        let p = ray.origin + ray.direction.scale(t);
+        // the field grows towards the centers, so the normal points down the gradient
+        let normal = (-self.gradient(&p))
+            .try_normalize(f64::EPSILON)
+            .unwrap_or(-ray.direction.normalize());
+        let (tangent, _) = orthonormal_basis(&normal);
+        Crossing {
+            t,
+            normal,
+            // blobs have no natural parameterization, so they are textured from above
+            uv: Vector2::new(p.x, p.z),
+            tangent,
+        }
+    }
+
+    // First crossing of the surface along the ray. The field only changes which balls it sums
+    // where the ray enters or leaves one, so it is searched segment by segment between those
+    // points, and each sign change found is refined by bisection.
+    pub fn intersect(&self, ray: &Ray) -> Option<Crossing> {
+        let d = ray.direction;
+        let mut bounds: Vec<f64> = self
+            .balls
+            .iter()
+            .flat_map(|b| {
+                let o = ray.origin.sub(b.center);
+                solve_quadratic(d.dot(&d), 2.0 * o.dot(&d), o.dot(&o) - b.radius * b.radius)
+            })
+            .filter(|&t| t > 0.0)
+            .collect();
+        bounds.push(0.0);
+        bounds.sort_by(|a, b| a.total_cmp(b));
+        let value = |t: f64| self.field(&(ray.origin + d.scale(t))) - self.threshold;
+        for segment in bounds.windows(2) {
+            let (start, end) = (segment[0], segment[1]);
+            let step = (end - start) / SAMPLES_PER_SEGMENT as f64;
+            let mut t0 = start;
+            let mut v0 = value(t0);
+            for i in 1..=SAMPLES_PER_SEGMENT {
+                let t1 = start + step * i as f64;
+                let v1 = value(t1);
+                if v0 * v1 <= 0.0 && v0 != v1 {
+                    let (mut low, mut high) = (t0, t1);
+                    for _ in 0..BISECTION_STEPS {
+                        let mid = 0.5 * (low + high);
+                        match value(mid) * v0 > 0.0 {
+                            true => low = mid,
+                            false => high = mid,
+                        }
+                    }
+                    return Some(self.crossing(ray, 0.5 * (low + high)));
+                }
+                t0 = t1;
+                v0 = v1;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod blob_tests {
+    use super::*;
+
+    fn blob(centers: &[f64]) -> Blob {
+        Blob {
+            threshold: 0.5,
+            balls: centers
+                .iter()
+                .map(|&x| Ball {
+                    center: Point3::new(x, 0.0, 0.0),
+                    radius: 1.0,
+                    weight: 1.0,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn single_ball_is_a_sphere() {
+        // w (1 - d^2)^3 = 0.5 where d^2 = 1 - 0.5^(1/3)
+        let r = (1.0 - 0.5f64.cbrt()).sqrt();
+        let ray = Ray::new(Point3::new(0.0, 0.0, -5.0), Vector3::new(0.0, 0.0, 1.0));
+        let hit = blob(&[0.0]).intersect(&ray).unwrap();
+        assert!((hit.t - (5.0 - r)).abs() < 1e-9);
+        assert!((hit.normal - Vector3::new(0.0, 0.0, -1.0)).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn nearby_balls_merge() {
+        // the point halfway between the balls is outside of each of them on their own
+        let ray = Ray::new(Point3::new(0.6, 0.0, -5.0), Vector3::new(0.0, 0.0, 1.0));
+        assert!(blob(&[0.0]).intersect(&ray).is_none());
+        assert!(blob(&[0.0, 1.2]).intersect(&ray).is_some());
+    }
+}
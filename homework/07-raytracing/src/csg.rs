@@ -0,0 +1,202 @@
+use crate::intersections::Crossing;
+use crate::models::{SceneObject, AABB};
+use std::str::FromStr;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    Difference,
+}
+
+impl FromStr for CsgOperation {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "union" => Ok(CsgOperation::Union),
+            "intersection" => Ok(CsgOperation::Intersection),
+            "difference" => Ok(CsgOperation::Difference),
+            _ => Err(format!("Unknown csg operation: {}", s)),
+        }
+    }
+}
+
+impl CsgOperation {
+    fn contains(&self, in_left: bool, in_right: bool) -> bool {
+        match self {
+            CsgOperation::Union => in_left || in_right,
+            CsgOperation::Intersection => in_left && in_right,
+            CsgOperation::Difference => in_left && !in_right,
+        }
+    }
+}
+
+// Every crossing of a ray with a solid, in order, along with whether the ray starts out inside
+// it. Each crossing remembers the object whose surface it lies on, which provides the material.
+#[derive(Debug, Clone)]
+pub struct Intervals {
+    pub starts_inside: bool,
+    pub crossings: Vec<(Crossing, Uuid)>,
+}
+
+// Two solids combined with a boolean operation
+#[derive(Debug, Clone, Copy)]
+pub struct CsgNode {
+    pub operation: CsgOperation,
+    pub left: SceneObject,
+    pub right: SceneObject,
+}
+
+impl CsgNode {
+    pub fn aabb(&self) -> Option<AABB> {
+        match (self.operation, self.left.aabb, self.right.aabb) {
+            (CsgOperation::Union, Some(a), Some(b)) => Some(a.union(&b)),
+            (CsgOperation::Union, _, _) => None,
+            (CsgOperation::Intersection, Some(a), Some(b)) => {
+                let min = a.min.sup(&b.min);
+                Some(AABB::new(min, a.max.inf(&b.max).sup(&min)))
+            }
+            (CsgOperation::Intersection, a, b) => a.or(b),
+            (CsgOperation::Difference, a, _) => a,
+        }
+    }
+
+    // Walks the crossings of both solids in order and keeps those where the ray moves into or
+    // out of the combined solid. Surfaces of a subtracted solid face the other way.
+    pub fn combine(&self, left: Intervals, right: Intervals) -> Intervals {
+        let mut in_left = left.starts_inside;
+        let mut in_right = right.starts_inside;
+        let starts_inside = self.operation.contains(in_left, in_right);
+        let mut inside = starts_inside;
+        let mut all: Vec<(Crossing, Uuid, bool)> = left
+            .crossings
+            .into_iter()
+            .map(|(c, id)| (c, id, true))
+            .chain(right.crossings.into_iter().map(|(c, id)| (c, id, false)))
+            .collect();
+        all.sort_by(|a, b| a.0.t.total_cmp(&b.0.t));
+        let mut crossings = vec![];
+        for (mut crossing, id, from_left) in all {
+            match from_left {
+                true => in_left = !in_left,
+                false => in_right = !in_right,
+            }
+            if self.operation.contains(in_left, in_right) == inside {
+                continue;
+            }
+            inside = !inside;
+            if !from_left && self.operation == CsgOperation::Difference {
+                crossing.normal = -crossing.normal;
+            }
+            crossings.push((crossing, id));
+        }
+        Intervals {
+            starts_inside,
+            crossings,
+        }
+    }
+}
+
+#[cfg(test)]
+mod csg_tests {
+    use super::*;
+    use crate::models::{ObjPrimative, DEFAULT_MATERIAL};
+    use crate::parser::{FileEntry, FileHeader, ProcFile};
+    use crate::scene::Scene;
+    use nalgebra::{Point3, Vector2, Vector3};
+
+    // a solid spanning `from` to `to` along a ray travelling down the x axis
+    fn interval(from: f64, to: f64) -> Intervals {
+        let crossing = |t: f64, x: f64| Crossing {
+            t,
+            normal: Vector3::new(x, 0.0, 0.0),
+            uv: Vector2::zeros(),
+            tangent: Vector3::new(0.0, 1.0, 0.0),
+        };
+        Intervals {
+            starts_inside: false,
+            crossings: vec![
+                (crossing(from, -1.0), Uuid::nil()),
+                (crossing(to, 1.0), Uuid::nil()),
+            ],
+        }
+    }
+
+    fn node(operation: CsgOperation) -> CsgNode {
+        let sphere = SceneObject::new(
+            ObjPrimative::Sphere {
+                xyz: Point3::origin(),
+                r: 1.0,
+            },
+            DEFAULT_MATERIAL,
+        );
+        CsgNode {
+            operation,
+            left: sphere,
+            right: sphere,
+        }
+    }
+
+    fn ts(intervals: &Intervals) -> Vec<f64> {
+        intervals.crossings.iter().map(|(c, _)| c.t).collect()
+    }
+
+    #[test]
+    fn union_merges_overlapping_intervals() {
+        let result = node(CsgOperation::Union).combine(interval(1.0, 3.0), interval(2.0, 4.0));
+        assert_eq!(vec![1.0, 4.0], ts(&result));
+    }
+
+    #[test]
+    fn intersection_keeps_the_overlap() {
+        let result =
+            node(CsgOperation::Intersection).combine(interval(1.0, 3.0), interval(2.0, 4.0));
+        assert_eq!(vec![2.0, 3.0], ts(&result));
+        assert_eq!(-1.0, result.crossings[0].0.normal.x);
+    }
+
+    #[test]
+    fn difference_flips_normals_of_the_subtracted_solid() {
+        let result = node(CsgOperation::Difference).combine(interval(1.0, 4.0), interval(2.0, 3.0));
+        assert_eq!(vec![1.0, 2.0, 3.0, 4.0], ts(&result));
+        // the walls of the hole face into it
+        assert_eq!(1.0, result.crossings[1].0.normal.x);
+        assert_eq!(-1.0, result.crossings[2].0.normal.x);
+    }
+
+    fn parse(lines: &[&str]) -> Result<Scene, String> {
+        let file = ProcFile {
+            header: FileHeader::from_str("png 10 10 csg.png").unwrap(),
+            entries: lines
+                .iter()
+                .map(|l| FileEntry::from_str(l).unwrap())
+                .collect(),
+        };
+        Scene::from_file(&file)
+    }
+
+    #[test]
+    fn only_capped_cylinders_and_cones_can_be_combined() {
+        for shape in ["cylinder", "cone"] {
+            let open = format!("{} 0 0 0 0 1 0 1", shape);
+            let capped = format!("{} capped", open);
+            assert!(parse(&["sphere 0 0 0 1", &open, "csg union"]).is_err());
+            assert!(parse(&["sphere 0 0 0 1", &capped, "csg union"]).is_ok());
+        }
+    }
+
+    #[test]
+    fn sdfs_can_not_be_combined() {
+        let sdf = "sdfbox 0 0 0 1 1 1 0.1";
+        assert!(parse(&[sdf]).is_ok());
+        assert!(parse(&["sphere 0 0 0 1", sdf, "csg difference"]).is_err());
+    }
+
+    #[test]
+    fn blobs_can_not_be_combined() {
+        let blob = ["blob 0.5", "ball 0 0 0 1 1", "ball 0.5 0 0 1 1", "end"];
+        assert!(parse(&blob).is_ok());
+        assert!(parse(&[&["sphere 0 0 0 1"], &blob[..], &["csg union"]].concat()).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use crate::models::{Material, ObjPrimative, SceneObject};
+use crate::transform::Transform;
+use crate::utils::orthonormal_basis;
+use nalgebra::{Point3, Vector2, Vector3};
+use std::f64::consts::PI;
+
+// Sphereflake around a sphere of radius 1 at the origin. Every sphere carries nine spheres a
+// third of its size, six around its equator and three above them, facing away from its parent.
+pub fn sphereflake(depth: usize, material: Material, transform: Transform) -> Vec<SceneObject> {
+    let mut spheres = vec![];
+    add_flake(
+        &mut spheres,
+        Point3::origin(),
+        1.0,
+        Vector3::new(0.0, 1.0, 0.0),
+        depth,
+    );
+    spheres
+        .into_iter()
+        .map(|(xyz, r)| {
+            SceneObject::transformed(ObjPrimative::Sphere { xyz, r }, material, transform)
+        })
+        .collect()
+}
+
+fn add_flake(
+    spheres: &mut Vec<(Point3<f64>, f64)>,
+    center: Point3<f64>,
+    r: f64,
+    up: Vector3<f64>,
+    depth: usize,
+) {
+    spheres.push((center, r));
+    if depth == 0 {
+        return;
+    }
+    let (u, w) = orthonormal_basis(&up);
+    let child = r / 3.0;
+    let ring = (0..6).map(|i| (0.0, i as f64 * PI / 3.0));
+    let top = (0..3).map(|i| (PI / 3.0, PI / 6.0 + i as f64 * 2.0 * PI / 3.0));
+    for (elevation, angle) in ring.chain(top) {
+        let direction = (u.scale(angle.cos()) + w.scale(angle.sin())).scale(elevation.cos())
+            + up.scale(elevation.sin());
+        let position = center + direction.scale(r + child);
+        add_flake(spheres, position, child, direction, depth - 1);
+    }
+}
+
+// whether a cell of a menger sponge with 3^depth cells along each side is solid, which it is
+// unless two of its coordinates fall in the middle third at any level
+fn menger_cell(mut cell: [i64; 3], depth: usize) -> bool {
+    let size = 3i64.pow(depth as u32);
+    if cell.iter().any(|&c| c < 0 || c >= size) {
+        return false;
+    }
+    for _ in 0..depth {
+        if cell.iter().filter(|&&c| c % 3 == 1).count() >= 2 {
+            return false;
+        }
+        cell = cell.map(|c| c / 3);
+    }
+    true
+}
+
+// Menger sponge filling the cube from -1 to 1. Only faces between a solid cell and an empty one
+// become triangles, so none are hidden inside of the sponge.
+pub fn menger(depth: usize, material: Material, transform: Transform) -> Vec<SceneObject> {
+    let size = 3i64.pow(depth as u32);
+    let cell_size = 2.0 / size as f64;
+    let uvs = [
+        Vector2::new(0.0, 0.0),
+        Vector2::new(1.0, 0.0),
+        Vector2::new(1.0, 1.0),
+        Vector2::new(0.0, 1.0),
+    ];
+    let mut objects = vec![];
+    for x in 0..size {
+        for y in 0..size {
+            for z in 0..size {
+                if !menger_cell([x, y, z], depth) {
+                    continue;
+                }
+                for axis in 0..3 {
+                    for side in [-1, 1] {
+                        let mut neighbour = [x, y, z];
+                        neighbour[axis] += side;
+                        if menger_cell(neighbour, depth) {
+                            continue;
+                        }
+                        // corners of the face, counter-clockwise when seen from outside
+                        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+                        let corners = [(0, 0), (1, 0), (1, 1), (0, 1)].map(|(i, j)| {
+                            let (i, j) = match side > 0 {
+                                true => (i, j),
+                                false => (j, i),
+                            };
+                            let mut corner = [x, y, z];
+                            corner[axis] += (side + 1) / 2;
+                            corner[a] += i;
+                            corner[b] += j;
+                            transform
+                                .point(&Point3::from(corner.map(|c| c as f64 * cell_size - 1.0)))
+                        });
+                        for [i, j, k] in [[0, 1, 2], [0, 2, 3]] {
+                            let primitive = ObjPrimative::triangle(
+                                [corners[i], corners[j], corners[k]],
+                                None,
+                                [uvs[i], uvs[j], uvs[k]],
+                            );
+                            objects.push(SceneObject::new(primitive, material));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    objects
+}
+
+#[cfg(test)]
+mod fractal_tests {
+    use super::*;
+    use crate::models::DEFAULT_MATERIAL;
+    use std::ops::Sub;
+
+    #[test]
+    fn sphereflake_children_touch_their_parent() {
+        let objects = sphereflake(2, DEFAULT_MATERIAL, Transform::identity());
+        assert_eq!(1 + 9 + 81, objects.len());
+        match objects[1].primitive {
+            ObjPrimative::Sphere { xyz, r } => {
+                assert!((r - 1.0 / 3.0).abs() < 1e-9);
+                assert!((xyz.coords.magnitude() - (1.0 + r)).abs() < 1e-9);
+            }
+            _ => panic!("expected a sphere"),
+        }
+    }
+
+    #[test]
+    fn menger_keeps_only_outside_faces() {
+        // 8 faces on each side of the cube and 8 in each of the three tunnels, two triangles each
+        let objects = menger(1, DEFAULT_MATERIAL, Transform::identity());
+        assert_eq!((6 * 8 + 3 * 8) * 2, objects.len());
+    }
+
+    #[test]
+    fn menger_faces_point_out_of_the_cube() {
+        for object in menger(1, DEFAULT_MATERIAL, Transform::identity()) {
+            if let ObjPrimative::Triangle { vertices, n, .. } = object.primitive {
+                let center = Point3::from(
+                    (vertices[0].coords + vertices[1].coords + vertices[2].coords).scale(1.0 / 3.0),
+                );
+                // faces on the outside of the cube point away from its center
+                if center.coords.amax() > 1.0 - 1e-9 {
+                    assert!(center.sub(Point3::origin()).dot(&n) > 0.0);
+                }
+            }
+        }
+    }
+}
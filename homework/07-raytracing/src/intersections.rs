@@ -0,0 +1,491 @@
+use crate::models::SceneObject;
+use crate::raytracer::{hit_error, sphere_tangents, sphere_uv, Ray, RayHit};
+use crate::solvers::{solve_quadratic, solve_quartic};
+use crate::utils::orthonormal_basis;
+use nalgebra::{Point3, Vector2, Vector3};
+use std::f64::consts::PI;
+use std::ops::Sub;
+
+// A point at which a ray crosses the surface of a primitive, given in the primitive's own space
+#[derive(Debug, Clone, Copy)]
+pub struct Crossing {
+    pub t: f64,
+    // always points out of the primitive
+    pub normal: Vector3<f64>,
+    pub uv: Vector2<f64>,
+    pub tangent: Vector3<f64>,
+}
+
+impl Crossing {
+    // turns the crossing into a hit, with the normal facing back against the ray
+    pub fn into_hit(self, ray: &Ray, object: &SceneObject) -> RayHit {
+        let surface_normal = match self.normal.dot(&ray.direction) > 0.0 {
+            true => -self.normal,
+            false => self.normal,
+        };
+        RayHit {
+            position: ray.origin + ray.direction.scale(self.t),
+            direction: ray.direction,
+            distance: self.t,
+            object_id: object.id,
+            surface_normal,
+            uv: self.uv,
+            tangent: self.tangent,
+            bitangent: self.normal.cross(&self.tangent),
+            geometric_normal: self.normal,
+            error: hit_error(ray, self.t),
+            placement: None,
+        }
+    }
+}
+
+// the first crossing in front of the ray's origin
+pub fn nearest(mut crossings: Vec<Crossing>) -> Option<Crossing> {
+    crossings.sort_by(|a, b| a.t.total_cmp(&b.t));
+    crossings.into_iter().find(|c| c.t > 0.0)
+}
+
+// One newton step on the function which is zero on a surface, taken along the ray from the root
+// `t`. The quadratic formula loses precision when the ray starts far from a small surface, which
+// the step wins back. `f` gives the value of the function and its gradient at a point.
+fn refine<F>(t: f64, o: &Vector3<f64>, d: &Vector3<f64>, f: F) -> f64
+where
+    F: Fn(&Vector3<f64>) -> (f64, Vector3<f64>),
+{
+    let (value, gradient) = f(&(o + d.scale(t)));
+    let slope = gradient.dot(d);
+    match slope == 0.0 {
+        true => t,
+        false => t - value / slope,
+    }
+}
+
+// Frame with the y axis along `axis`, used to intersect shapes which are symmetric around it
+struct AxisFrame {
+    origin: Point3<f64>,
+    u: Vector3<f64>,
+    axis: Vector3<f64>,
+    w: Vector3<f64>,
+}
+
+impl AxisFrame {
+    fn new(origin: Point3<f64>, axis: Vector3<f64>) -> Self {
+        // (u, axis, w) is right handed when w is the first vector of the basis around axis
+        let (w, u) = orthonormal_basis(&axis);
+        Self { origin, u, axis, w }
+    }
+
+    fn to_local(&self, ray: &Ray) -> (Vector3<f64>, Vector3<f64>) {
+        let o = ray.origin.sub(self.origin);
+        let d = ray.direction;
+        (
+            Vector3::new(o.dot(&self.u), o.dot(&self.axis), o.dot(&self.w)),
+            Vector3::new(d.dot(&self.u), d.dot(&self.axis), d.dot(&self.w)),
+        )
+    }
+
+    fn to_world(&self, v: &Vector3<f64>) -> Vector3<f64> {
+        self.u.scale(v.x) + self.axis.scale(v.y) + self.w.scale(v.z)
+    }
+
+    // crossing on the curved side of the shape, textured by the angle around the axis
+    fn side_crossing(&self, t: f64, p: &Vector3<f64>, normal: Vector3<f64>, v: f64) -> Crossing {
+        let angle = p.x.atan2(p.z);
+        Crossing {
+            t,
+            normal: self.to_world(&normal).normalize(),
+            uv: Vector2::new(0.5 + angle / (2.0 * PI), v),
+            tangent: self.to_world(&Vector3::new(p.z, 0.0, -p.x)).normalize(),
+        }
+    }
+
+    // crossing with a flat cap at height `y`, facing `facing` along the axis
+    fn cap_crossing(
+        &self,
+        o: &Vector3<f64>,
+        d: &Vector3<f64>,
+        y: f64,
+        r: f64,
+        facing: f64,
+    ) -> Option<Crossing> {
+        if d.y == 0.0 {
+            return None;
+        }
+        let t = (y - o.y) / d.y;
+        let p = o + d.scale(t);
+        match p.x * p.x + p.z * p.z <= r * r {
+            true => Some(Crossing {
+                t,
+                normal: self.axis.scale(facing),
+                // v is mirrored on caps facing down the axis to keep the texture right way round
+                uv: Vector2::new(0.5 + 0.5 * p.x / r, 0.5 - 0.5 * facing * p.z / r),
+                tangent: self.u,
+            }),
+            false => None,
+        }
+    }
+}
+
+pub fn sphere_crossings(ray: &Ray, center: Point3<f64>, r: f64) -> Vec<Crossing> {
+    let o = ray.origin.sub(center);
+    let d = ray.direction;
+    solve_quadratic(d.dot(&d), 2.0 * o.dot(&d), o.dot(&o) - r * r)
+        .into_iter()
+        .map(|t| {
+            let normal = (o + d.scale(t)).scale(1.0 / r);
+            Crossing {
+                t,
+                normal,
+                uv: sphere_uv(&normal),
+                tangent: sphere_tangents(&normal).0,
+            }
+        })
+        .collect()
+}
+
+// plane through `p` with the unit normal `n`, solid on the side facing away from the normal
+pub fn plane_crossings(ray: &Ray, n: Vector3<f64>, p: Point3<f64>) -> Vec<Crossing> {
+    let den = ray.direction.dot(&n);
+    if den == 0.0 {
+        return vec![];
+    }
+    let t = p.sub(ray.origin).dot(&n) / den;
+    let (tangent, bitangent) = orthonormal_basis(&n);
+    let offset = ray.origin + ray.direction.scale(t) - p;
+    vec![Crossing {
+        t,
+        normal: n,
+        uv: Vector2::new(offset.dot(&tangent), offset.dot(&bitangent)),
+        tangent,
+    }]
+}
+
+// cylinder of radius `r` going `height` along the unit `axis` from `base`
+pub fn cylinder_crossings(
+    ray: &Ray,
+    base: Point3<f64>,
+    axis: Vector3<f64>,
+    height: f64,
+    r: f64,
+    capped: bool,
+) -> Vec<Crossing> {
+    let frame = AxisFrame::new(base, axis);
+    let (o, d) = frame.to_local(ray);
+    let a = d.x * d.x + d.z * d.z;
+    let b = 2.0 * (o.x * d.x + o.z * d.z);
+    let c = o.x * o.x + o.z * o.z - r * r;
+    let side = |p: &Vector3<f64>| {
+        let value = p.x * p.x + p.z * p.z - r * r;
+        (value, Vector3::new(2.0 * p.x, 0.0, 2.0 * p.z))
+    };
+    let mut crossings: Vec<Crossing> = solve_quadratic(a, b, c)
+        .into_iter()
+        .map(|t| refine(t, &o, &d, side))
+        .filter_map(|t| {
+            let p = o + d.scale(t);
+            match p.y >= 0.0 && p.y <= height {
+                true => Some(frame.side_crossing(t, &p, Vector3::new(p.x, 0.0, p.z), p.y / height)),
+                false => None,
+            }
+        })
+        .collect();
+    if capped {
+        crossings.extend(frame.cap_crossing(&o, &d, 0.0, r, -1.0));
+        crossings.extend(frame.cap_crossing(&o, &d, height, r, 1.0));
+    }
+    crossings.sort_by(|a, b| a.t.total_cmp(&b.t));
+    crossings
+}
+
+// cone with a base of radius `r` at `base` narrowing to a point `height` along the unit `axis`
+pub fn cone_crossings(
+    ray: &Ray,
+    base: Point3<f64>,
+    axis: Vector3<f64>,
+    height: f64,
+    r: f64,
+    capped: bool,
+) -> Vec<Crossing> {
+    let frame = AxisFrame::new(base, axis);
+    let (o, d) = frame.to_local(ray);
+    let slope = (r / height).powi(2);
+    let a = d.x * d.x + d.z * d.z - slope * d.y * d.y;
+    let b = 2.0 * (o.x * d.x + o.z * d.z + slope * (height - o.y) * d.y);
+    let c = o.x * o.x + o.z * o.z - slope * (height - o.y).powi(2);
+    let side = |p: &Vector3<f64>| {
+        let value = p.x * p.x + p.z * p.z - slope * (height - p.y).powi(2);
+        let gradient = Vector3::new(2.0 * p.x, 2.0 * slope * (height - p.y), 2.0 * p.z);
+        (value, gradient)
+    };
+    let mut crossings: Vec<Crossing> = solve_quadratic(a, b, c)
+        .into_iter()
+        .map(|t| refine(t, &o, &d, side))
+        .filter_map(|t| {
+            let p = o + d.scale(t);
+            match p.y >= 0.0 && p.y <= height {
+                true => {
+                    let normal = Vector3::new(p.x, slope * (height - p.y), p.z);
+                    Some(frame.side_crossing(t, &p, normal, p.y / height))
+                }
+                false => None,
+            }
+        })
+        .collect();
+    if capped {
+        crossings.extend(frame.cap_crossing(&o, &d, 0.0, r, -1.0));
+    }
+    crossings.sort_by(|a, b| a.t.total_cmp(&b.t));
+    crossings
+}
+
+// flat disk of radius `r` around `center`, facing along the unit normal `n`
+pub fn disk_crossings(ray: &Ray, center: Point3<f64>, n: Vector3<f64>, r: f64) -> Vec<Crossing> {
+    let frame = AxisFrame::new(center, n);
+    let (o, d) = frame.to_local(ray);
+    frame
+        .cap_crossing(&o, &d, 0.0, r, 1.0)
+        .into_iter()
+        .collect()
+}
+
+// axis aligned box, found with the slab method
+pub fn box_crossings(ray: &Ray, min: Point3<f64>, max: Point3<f64>) -> Vec<Crossing> {
+    let mut t_near = f64::NEG_INFINITY;
+    let mut t_far = f64::INFINITY;
+    let mut near_axis = 0;
+    let mut far_axis = 0;
+    for i in 0..3 {
+        let inv_d = 1.0 / ray.direction[i];
+        let mut t1 = (min[i] - ray.origin[i]) * inv_d;
+        let mut t2 = (max[i] - ray.origin[i]) * inv_d;
+        if inv_d < 0.0 {
+            std::mem::swap(&mut t1, &mut t2);
+        }
+        if t1 > t_near {
+            t_near = t1;
+            near_axis = i;
+        }
+        if t2 < t_far {
+            t_far = t2;
+            far_axis = i;
+        }
+    }
+    if t_near > t_far || !t_near.is_finite() || !t_far.is_finite() {
+        return vec![];
+    }
+    let size = max.sub(min);
+    let crossing = |t: f64, axis: usize, sign: f64| {
+        let p = ray.origin + ray.direction.scale(t) - min;
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        let mut normal = Vector3::zeros();
+        normal[axis] = sign;
+        let mut tangent = Vector3::zeros();
+        tangent[a] = 1.0;
+        let v = match sign > 0.0 {
+            true => p[b] / size[b],
+            false => 1.0 - p[b] / size[b],
+        };
+        Crossing {
+            t,
+            normal,
+            uv: Vector2::new(p[a] / size[a], v),
+            tangent,
+        }
+    };
+    // the ray enters through the face it is moving into and leaves through the opposite one
+    vec![
+        crossing(t_near, near_axis, -ray.direction[near_axis].signum()),
+        crossing(t_far, far_axis, ray.direction[far_axis].signum()),
+    ]
+}
+
+// torus around the unit `axis` through `center`, with a tube of radius `minor` swept along a
+// circle of radius `major`
+pub fn torus_crossings(
+    ray: &Ray,
+    center: Point3<f64>,
+    axis: Vector3<f64>,
+    major: f64,
+    minor: f64,
+) -> Vec<Crossing> {
+    let frame = AxisFrame::new(center, axis);
+    let (o, d) = frame.to_local(ray);
+    // the quartic is solved along a unit direction starting from the point on the ray closest to
+    // the center, which keeps its coefficients small and well conditioned
+    let length = d.magnitude();
+    let d = d.scale(1.0 / length);
+    let shift = -o.dot(&d);
+    let o = o + d.scale(shift);
+    if o.magnitude_squared() > (major + minor).powi(2) {
+        return vec![];
+    }
+    let k = o.magnitude_squared() + major * major - minor * minor;
+    let od = o.dot(&d);
+    let ring = 4.0 * major * major;
+    solve_quartic(
+        1.0,
+        4.0 * od,
+        4.0 * od * od + 2.0 * k - ring * (d.x * d.x + d.z * d.z),
+        4.0 * od * k - 2.0 * ring * (o.x * d.x + o.z * d.z),
+        k * k - ring * (o.x * o.x + o.z * o.z),
+    )
+    .into_iter()
+    .map(|s| {
+        let p = o + d.scale(s);
+        // gradient of (|p|^2 + R^2 - r^2)^2 - 4 R^2 (x^2 + z^2), divided by four
+        let sum = p.magnitude_squared() + major * major - minor * minor;
+        let normal = Vector3::new(
+            p.x * (sum - 2.0 * major * major),
+            p.y * sum,
+            p.z * (sum - 2.0 * major * major),
+        );
+        // v runs around the tube, starting on its outside
+        let outward = (p.x * p.x + p.z * p.z).sqrt() - major;
+        let v = 0.5 + p.y.atan2(outward) / (2.0 * PI);
+        frame.side_crossing((s + shift) / length, &p, normal, v)
+    })
+    .collect()
+}
+
+// value of the quadric's expression at `p`, negative inside of it
+pub fn quadric_value(q: &[f64; 10], p: &Point3<f64>) -> f64 {
+    let [qa, qb, qc, qd, qe, qf, qg, qh, qi, qj] = *q;
+    qa * p.x * p.x
+        + qb * p.y * p.y
+        + qc * p.z * p.z
+        + qd * p.x * p.y
+        + qe * p.x * p.z
+        + qf * p.y * p.z
+        + qg * p.x
+        + qh * p.y
+        + qi * p.z
+        + qj
+}
+
+fn quadric_gradient(q: &[f64; 10], p: &Point3<f64>) -> Vector3<f64> {
+    let [qa, qb, qc, qd, qe, qf, qg, qh, qi, _] = *q;
+    Vector3::new(
+        2.0 * qa * p.x + qd * p.y + qe * p.z + qg,
+        2.0 * qb * p.y + qd * p.x + qf * p.z + qh,
+        2.0 * qc * p.z + qe * p.x + qf * p.y + qi,
+    )
+}
+
+// surface where A x^2 + B y^2 + C z^2 + D xy + E xz + F yz + G x + H y + I z + J = 0, with the
+// inside where the expression is negative
+pub fn quadric_crossings(ray: &Ray, q: &[f64; 10]) -> Vec<Crossing> {
+    let [qa, qb, qc, qd, qe, qf, qg, qh, qi, _] = *q;
+    let o = ray.origin;
+    let d = ray.direction;
+    let a = qa * d.x * d.x
+        + qb * d.y * d.y
+        + qc * d.z * d.z
+        + qd * d.x * d.y
+        + qe * d.x * d.z
+        + qf * d.y * d.z;
+    let b = 2.0 * (qa * o.x * d.x + qb * o.y * d.y + qc * o.z * d.z)
+        + qd * (o.x * d.y + o.y * d.x)
+        + qe * (o.x * d.z + o.z * d.x)
+        + qf * (o.y * d.z + o.z * d.y)
+        + qg * d.x
+        + qh * d.y
+        + qi * d.z;
+    let surface = |p: &Vector3<f64>| {
+        let p = Point3::from(*p);
+        (quadric_value(q, &p), quadric_gradient(q, &p))
+    };
+    solve_quadratic(a, b, quadric_value(q, &o))
+        .into_iter()
+        .map(|t| refine(t, &o.coords, &d, surface))
+        .filter_map(|t| {
+            let p = o + d.scale(t);
+            let gradient = quadric_gradient(q, &p);
+            // the gradient vanishes at singular points such as the tip of a double cone
+            let normal = gradient.try_normalize(f64::EPSILON)?;
+            let (tangent, _) = orthonormal_basis(&normal);
+            Some(Crossing {
+                t,
+                normal,
+                // quadrics have no natural parameterization, so they are textured from above
+                uv: Vector2::new(p.x, p.z),
+                tangent,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod intersection_tests {
+    use super::*;
+
+    #[test]
+    fn cylinder_side_and_caps() {
+        let axis = Vector3::new(0.0, 1.0, 0.0);
+        let across = Ray::new(Point3::new(-5.0, 0.5, 0.0), Vector3::new(1.0, 0.0, 0.0));
+        let crossings = cylinder_crossings(&across, Point3::origin(), axis, 1.0, 1.0, true);
+        assert_eq!(2, crossings.len());
+        assert!((crossings[0].t - 4.0).abs() < 1e-9);
+        assert!((crossings[0].normal - Vector3::new(-1.0, 0.0, 0.0)).magnitude() < 1e-9);
+
+        let down = Ray::new(Point3::new(0.5, 5.0, 0.0), Vector3::new(0.0, -1.0, 0.0));
+        let open = cylinder_crossings(&down, Point3::origin(), axis, 1.0, 1.0, false);
+        let capped = cylinder_crossings(&down, Point3::origin(), axis, 1.0, 1.0, true);
+        assert!(open.is_empty());
+        assert_eq!(2, capped.len());
+        assert!((capped[0].t - 4.0).abs() < 1e-9);
+        assert!((capped[0].normal - axis).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn cone_narrows_towards_its_apex() {
+        let axis = Vector3::new(0.0, 1.0, 0.0);
+        let low = Ray::new(Point3::new(-5.0, 0.5, 0.0), Vector3::new(1.0, 0.0, 0.0));
+        let crossings = cone_crossings(&low, Point3::origin(), axis, 1.0, 1.0, false);
+        assert_eq!(2, crossings.len());
+        assert!((crossings[0].t - 4.5).abs() < 1e-9);
+        assert!((crossings[1].t - 5.5).abs() < 1e-9);
+
+        let high = Ray::new(Point3::new(-5.0, 1.5, 0.0), Vector3::new(1.0, 0.0, 0.0));
+        assert!(cone_crossings(&high, Point3::origin(), axis, 1.0, 1.0, false).is_empty());
+    }
+
+    #[test]
+    fn box_reports_entry_and_exit_faces() {
+        let ray = Ray::new(Point3::new(0.5, 0.5, 5.0), Vector3::new(0.0, 0.0, -1.0));
+        let crossings = box_crossings(&ray, Point3::origin(), Point3::new(1.0, 1.0, 1.0));
+        assert_eq!(2, crossings.len());
+        assert!((crossings[0].t - 4.0).abs() < 1e-9);
+        assert!((crossings[0].normal - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1e-9);
+        assert!((crossings[1].normal - Vector3::new(0.0, 0.0, -1.0)).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn torus_is_crossed_through_both_sides_of_its_tube() {
+        let axis = Vector3::new(0.0, 1.0, 0.0);
+        // along the x axis from far away, passing through the hole in the middle
+        let ray = Ray::new(Point3::new(-100.0, 0.0, 0.0), Vector3::new(2.0, 0.0, 0.0));
+        let crossings = torus_crossings(&ray, Point3::origin(), axis, 2.0, 0.5);
+        let ts: Vec<f64> = crossings.iter().map(|c| c.t).collect();
+        let expected = [97.5, 98.5, 101.5, 102.5].map(|x| x / 2.0);
+        assert_eq!(4, ts.len(), "{:?}", ts);
+        for (e, t) in expected.iter().zip(&ts) {
+            assert!((e - t).abs() < 1e-6, "{:?}", ts);
+        }
+        assert!((crossings[0].normal - Vector3::new(-1.0, 0.0, 0.0)).magnitude() < 1e-6);
+        assert!((crossings[1].normal - Vector3::new(1.0, 0.0, 0.0)).magnitude() < 1e-6);
+
+        let above = Ray::new(Point3::new(-100.0, 1.0, 0.0), Vector3::new(1.0, 0.0, 0.0));
+        assert!(torus_crossings(&above, Point3::origin(), axis, 2.0, 0.5).is_empty());
+    }
+
+    #[test]
+    fn quadric_unit_sphere() {
+        let sphere = [1.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, -1.0];
+        let ray = Ray::new(Point3::new(0.0, 0.0, -5.0), Vector3::new(0.0, 0.0, 1.0));
+        let crossings = quadric_crossings(&ray, &sphere);
+        assert_eq!(2, crossings.len());
+        assert!((crossings[0].t - 4.0).abs() < 1e-9);
+        assert!((crossings[0].normal - Vector3::new(0.0, 0.0, -1.0)).magnitude() < 1e-9);
+        assert!((crossings[1].normal - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1e-9);
+    }
+}
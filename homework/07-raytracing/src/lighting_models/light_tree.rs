@@ -0,0 +1,189 @@
+use crate::models::AABB;
+use crate::utils::Rng;
+use nalgebra::{Point3, Vector3};
+use std::ops::Sub;
+
+#[derive(Debug)]
+enum LightNodeKind {
+    // index of the light in the list the tree was built from
+    Leaf(usize),
+    // indices of the two child nodes
+    Inner(usize, usize),
+}
+
+#[derive(Debug)]
+struct LightNode {
+    bounds: AABB,
+    // summed brightness of every light below the node
+    power: f64,
+    kind: LightNodeKind,
+}
+
+// Bounding volume hierarchy over point lights, which picks a light in time logarithmic in
+// their number. Each step down the tree picks a child with a chance proportional to how much
+// light it could send to the point being lit.
+#[derive(Debug)]
+pub struct LightTree {
+    nodes: Vec<LightNode>,
+}
+
+impl LightTree {
+    // `lights` pairs the position of each light with its color
+    pub fn new(lights: &[(Point3<f64>, Vector3<f64>)]) -> Self {
+        let mut tree = Self { nodes: vec![] };
+        let mut indices: Vec<usize> = (0..lights.len()).collect();
+        if !indices.is_empty() {
+            tree.build(lights, &mut indices);
+        }
+        tree
+    }
+
+    // adds the nodes for the given lights, returning the index of their root
+    fn build(&mut self, lights: &[(Point3<f64>, Vector3<f64>)], indices: &mut [usize]) -> usize {
+        let bounds = indices
+            .iter()
+            .map(|&i| AABB::new(lights[i].0, lights[i].0))
+            .reduce(|a, b| a.union(&b))
+            .unwrap();
+        // negative lights take light away, which is as important as adding it
+        let power = indices.iter().map(|&i| lights[i].1.abs().sum()).sum();
+        let kind = match indices {
+            [i] => LightNodeKind::Leaf(*i),
+            _ => {
+                let axis = bounds.max.sub(bounds.min).imax();
+                let middle = indices.len() / 2;
+                indices.select_nth_unstable_by(middle, |&a, &b| {
+                    lights[a].0[axis].total_cmp(&lights[b].0[axis])
+                });
+                let (left, right) = indices.split_at_mut(middle);
+                LightNodeKind::Inner(self.build(lights, left), self.build(lights, right))
+            }
+        };
+        self.nodes.push(LightNode {
+            bounds,
+            power,
+            kind,
+        });
+        self.nodes.len() - 1
+    }
+
+    // Upper estimate of the light a node sends to `p`. Nodes lying entirely below the surface
+    // with the normal `n` send it none.
+    fn importance(&self, node: usize, p: &Point3<f64>, n: Option<&Vector3<f64>>) -> f64 {
+        let node = &self.nodes[node];
+        let (min, max) = (node.bounds.min, node.bounds.max);
+        if let Some(n) = n {
+            let corners = (0..8).map(|c| {
+                Point3::new(
+                    [min.x, max.x][c & 1],
+                    [min.y, max.y][c >> 1 & 1],
+                    [min.z, max.z][c >> 2 & 1],
+                )
+            });
+            if corners.map(|c| c.sub(p).dot(n)).all(|d| d <= 0.0) {
+                return 0.0;
+            }
+        }
+        let center = min + max.sub(min).scale(0.5);
+        let radius = max.sub(min).magnitude() / 2.0;
+        let distance = center.sub(p).magnitude();
+        // lights inside of the node could be as close as its edge
+        let closest = (distance - radius).max(radius).max(f64::EPSILON);
+        node.power / (closest * closest)
+    }
+
+    // Picks a light to light `p` with, and the chance that it was picked
+    pub fn sample(
+        &self,
+        p: &Point3<f64>,
+        n: Option<&Vector3<f64>>,
+        rng: &Rng,
+    ) -> Option<(usize, f64)> {
+        // the root is added last
+        let mut node = self.nodes.len().checked_sub(1)?;
+        let mut chance = 1.0;
+        loop {
+            match self.nodes[node].kind {
+                LightNodeKind::Leaf(light) => return Some((light, chance)),
+                LightNodeKind::Inner(left, right) => {
+                    let l = self.importance(left, p, n);
+                    let r = self.importance(right, p, n);
+                    if l + r <= 0.0 {
+                        return None;
+                    }
+                    let left_chance = l / (l + r);
+                    match rng.next_f64() < left_chance {
+                        true => {
+                            node = left;
+                            chance *= left_chance;
+                        }
+                        false => {
+                            node = right;
+                            chance *= 1.0 - left_chance;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod light_tree_tests {
+    use super::*;
+
+    fn lights() -> Vec<(Point3<f64>, Vector3<f64>)> {
+        (0..40)
+            .map(|i| {
+                let x = (i % 8) as f64 - 3.5;
+                let z = (i / 8) as f64 - 2.0;
+                let brightness = 1.0 + (i % 3) as f64;
+                (
+                    Point3::new(x, 1.0 + (i % 2) as f64, z),
+                    Vector3::new(brightness, brightness, brightness),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sampled_lights_average_to_the_sum_over_every_light() {
+        let lights = lights();
+        let tree = LightTree::new(&lights);
+        let p = Point3::new(0.3, 0.0, 0.2);
+        let received = |(position, color): &(Point3<f64>, Vector3<f64>)| {
+            color.x / position.sub(p).magnitude_squared()
+        };
+        let exact: f64 = lights.iter().map(received).sum();
+        let rng = Rng::new(11);
+        let runs = 50000;
+        let estimate: f64 = (0..runs)
+            .map(|_| {
+                let (light, chance) = tree.sample(&p, None, &rng).unwrap();
+                received(&lights[light]) / chance
+            })
+            .sum();
+        assert!((estimate / runs as f64 - exact).abs() < 0.01 * exact);
+    }
+
+    #[test]
+    fn lights_below_the_surface_are_never_picked() {
+        let mut lights = lights();
+        lights.push((
+            Point3::new(0.0, -1.0, 0.0),
+            Vector3::new(100.0, 100.0, 100.0),
+        ));
+        let tree = LightTree::new(&lights);
+        let rng = Rng::new(2);
+        let up = Vector3::new(0.0, 1.0, 0.0);
+        for _ in 0..1000 {
+            let (light, _) = tree.sample(&Point3::origin(), Some(&up), &rng).unwrap();
+            assert!(lights[light].0.y > 0.0);
+        }
+        let down = -up;
+        assert_eq!(
+            Some((40, 1.0)),
+            tree.sample(&Point3::origin(), Some(&down), &rng)
+        );
+    }
+}
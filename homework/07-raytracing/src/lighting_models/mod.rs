@@ -1,62 +1,297 @@
 use crate::lighting_models::lambert::LambertLighting;
-use crate::models::LightPrimitive;
-use crate::parser::ProcFile;
+use crate::lighting_models::light_tree::LightTree;
+use crate::medium::Interior;
+use crate::models::{LightPrimitive, LightSourceObject};
+use crate::parser::{FileEntry, ProcFile};
 use crate::raytracer::{Ray, RayHit, RayTracer};
 use crate::scene::Scene;
-use nalgebra::Vector3;
+use crate::utils::Rng;
+use nalgebra::{Point3, Vector3};
+use std::collections::HashMap;
 use std::ops::{Add, Sub};
+use uuid::Uuid;
 
 mod lambert;
+mod light_tree;
+
+// ratio tracking estimates averaged for each shadow ray through a volume
+const VOLUME_SHADOW_SAMPLES: usize = 4;
 
 pub struct LightingModel<'a> {
     lambert: LambertLighting,
     scene: &'a Scene,
     ray_tracer: RayTracer<'a>,
+    // picks the steps of ratio tracking through volumes, and the bulbs sampled from the tree
+    rng: Rng,
+    // indices of the bulbs among the scene's lights, in the order the tree was built from
+    bulbs: Vec<usize>,
+    // tree over the bulbs, and how many of them light each hit, when they are sampled
+    light_tree: Option<(LightTree, usize)>,
 }
 
 impl<'a> LightingModel<'a> {
-    pub fn from_file(_file: &ProcFile, scene: &'a Scene) -> Self {
+    pub fn from_file(file: &ProcFile, scene: &'a Scene) -> Self {
+        let bulbs: Vec<usize> = scene
+            .light_sources
+            .iter()
+            .enumerate()
+            .filter(|(_, l)| matches!(l.source, LightPrimitive::Point(_)))
+            .map(|(i, _)| i)
+            .collect();
+        let light_samples = file.entries.iter().find_map(|e| match e {
+            FileEntry::LightSamples { n } if *n > 0 => Some(*n),
+            _ => None,
+        });
+        let light_tree = light_samples.map(|n| {
+            let lights: Vec<(Point3<f64>, Vector3<f64>)> = bulbs
+                .iter()
+                .map(|&i| &scene.light_sources[i])
+                .map(|light| match light.source {
+                    LightPrimitive::Point(p) => (p, light.color.scale(light.intensity)),
+                    LightPrimitive::Directional(_) => unreachable!(),
+                })
+                .collect();
+            (LightTree::new(&lights), n)
+        });
         Self {
             lambert: LambertLighting {},
             scene,
             ray_tracer: RayTracer::new(scene),
+            rng: Rng::new(1),
+            bulbs,
+            light_tree,
+        }
+    }
+
+    // Lights which are added up in full, which is every light unless bulbs are sampled
+    fn exact_lights(&self) -> impl Iterator<Item = &LightSourceObject> {
+        let sampled = self.light_tree.is_some();
+        self.scene
+            .light_sources
+            .iter()
+            .filter(move |l| !sampled || matches!(l.source, LightPrimitive::Directional(_)))
+    }
+
+    // Bulbs picked from the light tree, each weighted by one over the chance of picking it so
+    // that on average they add up to the light from every bulb
+    fn sampled_bulbs(
+        &self,
+        position: &Point3<f64>,
+        normal: Option<&Vector3<f64>>,
+    ) -> Vec<(&LightSourceObject, f64)> {
+        match &self.light_tree {
+            Some((tree, samples)) => (0..*samples)
+                .filter_map(|_| tree.sample(position, normal, &self.rng))
+                .map(|(bulb, chance)| {
+                    let light = &self.scene.light_sources[self.bulbs[bulb]];
+                    (light, 1.0 / (chance * *samples as f64))
+                })
+                .collect(),
+            None => vec![],
         }
     }
 
     // Gets the light color incident to a surface from the lights in a scene
     pub fn light(&self, hit: &RayHit) -> Vector3<f64> {
         let mut result = Vector3::<f64>::zeros();
-        for light in &self.scene.light_sources {
+        let exact = self.exact_lights().map(|l| (l, 1.0));
+        let sampled = self.sampled_bulbs(&hit.position, Some(&hit.surface_normal));
+        for (light, weight) in exact.chain(sampled) {
             let light_result: Option<Vector3<f64>> = match light.source {
                 LightPrimitive::Directional(d) => {
-                    let shadow_ray = Ray::new(hit.position, d);
-                    match self.ray_tracer.trace_ray(&shadow_ray, Some(hit.object_id)) {
-                        Some(_) => None,
-                        None => {
+                    match self.transmittance(hit.spawn_ray(d), f64::INFINITY) {
+                        t if t == Vector3::zeros() => None,
+                        t => {
                             let dist = self.lambert.get_distribution(&d, &hit.surface_normal);
-                            Some(light.color.scale(dist))
+                            let color = light.brightness(f64::INFINITY).component_mul(&t);
+                            Some(color.scale(dist))
                         }
                     }
                 }
                 LightPrimitive::Point(p) => {
                     let d = p.sub(hit.position);
-                    let shadow_ray = Ray::new(hit.position, d.normalize());
-                    match self.ray_tracer.trace_ray(&shadow_ray, Some(hit.object_id)) {
-                        Some(h) if h.distance < d.magnitude() => None,
-                        _ => {
+                    let distance = d.magnitude();
+                    let direction = d.normalize();
+                    match self.transmittance(hit.spawn_ray(direction), distance) {
+                        t if t == Vector3::zeros() => None,
+                        t => {
                             let dist = self.lambert.get_distribution(&d, &hit.surface_normal);
-                            Some(light.color.scale(dist / d.magnitude_squared()))
+                            Some(light.brightness(distance).component_mul(&t).scale(dist))
                         }
                     }
                 }
             };
             match light_result {
                 Some(v) => {
-                    result = result.add(&v);
+                    result = result.add(&v.scale(weight));
                 }
                 None => continue,
             }
         }
         result
     }
+
+    // Light reaching a point inside of a medium from every light, which the medium scatters
+    // equally in every direction
+    pub fn scattered_light(&self, position: &Point3<f64>) -> Vector3<f64> {
+        let mut result = Vector3::<f64>::zeros();
+        let exact = self.exact_lights().map(|l| (l, 1.0));
+        let sampled = self.sampled_bulbs(position, None);
+        for (light, weight) in exact.chain(sampled) {
+            result += match light.source {
+                LightPrimitive::Directional(d) => {
+                    let t = self.transmittance(Ray::new(*position, d), f64::INFINITY);
+                    light
+                        .brightness(f64::INFINITY)
+                        .component_mul(&t)
+                        .scale(weight)
+                }
+                LightPrimitive::Point(p) => {
+                    let d = p.sub(position);
+                    let t = self.transmittance(Ray::new(*position, d.normalize()), d.magnitude());
+                    light
+                        .brightness(d.magnitude())
+                        .component_mul(&t)
+                        .scale(weight)
+                }
+            };
+        }
+        result
+    }
+
+    // Fraction of a light `distance` away along the unit `direction` which reaches `origin`, in
+    // each color. Surfaces of objects filled with a medium or volume let light through, dimmed
+    // by what lies between them, transparent surfaces filter it, and any other surface blocks
+    // it. Sunlight is taken to come from above the fog, so only bulbs are dimmed by it.
+    fn transmittance(&self, shadow_ray: Ray, distance: f64) -> Vector3<f64> {
+        let mut result = match (self.scene.fog, distance.is_finite()) {
+            (Some(fog), true) => Vector3::repeat(fog.transmittance(distance)),
+            _ => Vector3::repeat(1.0),
+        };
+        // distance along the ray at which it entered each medium it is still in
+        let mut entered: HashMap<Uuid, f64> = HashMap::new();
+        let mut travelled = 0.0;
+        let direction = shadow_ray.direction;
+        let mut ray = Ray::new(shadow_ray.origin, direction);
+        while let Some(hit) = self.ray_tracer.trace_ray(&ray) {
+            if travelled + hit.distance >= distance {
+                break;
+            }
+            let object = &self.scene.hit_object(&hit).unwrap();
+            travelled += hit.distance;
+            match (self.scene.interior(object), object.material.transparency) {
+                (None, 0.0) => return Vector3::zeros(),
+                // every surface of a transparent object filters the light crossing it
+                (None, t) => {
+                    let color = self.scene.surface_color(&hit, object);
+                    result = result.component_mul(&color.scale(t))
+                }
+                (Some(_), _) if direction.dot(&hit.geometric_normal) < 0.0 => {
+                    entered.insert(hit.object_id, travelled);
+                }
+                // leaving a medium the ray may have started out in
+                (Some(interior), _) => {
+                    let start = entered.remove(&hit.object_id).unwrap_or(0.0);
+                    result *= match interior {
+                        Interior::Medium(m) => m.transmittance(travelled - start),
+                        Interior::Volume(v) => {
+                            let volume = &self.scene.volumes[v];
+                            let estimates: f64 = (0..VOLUME_SHADOW_SAMPLES)
+                                .map(|_| {
+                                    volume.transmittance(&shadow_ray, start, travelled, &self.rng)
+                                })
+                                .sum();
+                            estimates / VOLUME_SHADOW_SAMPLES as f64
+                        }
+                    };
+                }
+            }
+            ray = hit.spawn_ray(direction);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod lighting_tests {
+    use super::*;
+    use crate::parser::FileHeader;
+    use std::str::FromStr;
+
+    #[test]
+    fn transparent_surfaces_cast_colored_shadows() {
+        let lines = [
+            "bulb 0 4 0",
+            "color 1 0.5 0",
+            "transparency 0.5",
+            "plane 0 1 0 -2",
+            "color 1 1 1",
+            "transparency 0",
+            "plane 0 1 0 0",
+        ];
+        let file = ProcFile {
+            header: FileHeader::from_str("png 10 10 shadows.png").unwrap(),
+            entries: lines
+                .iter()
+                .map(|l| FileEntry::from_str(l).unwrap())
+                .collect(),
+        };
+        let scene = Scene::from_file(&file).unwrap();
+        let lighting_model = LightingModel::from_file(&file, &scene);
+        let ray = Ray::new(Point3::new(0.0, 1.0, 0.0), Vector3::new(0.0, -1.0, 0.0));
+        let hit = lighting_model.ray_tracer.trace_ray(&ray).unwrap();
+        let light = lighting_model.light(&hit);
+        assert!((light - Vector3::new(0.5, 0.25, 0.0).scale(1.0 / 16.0)).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn transparent_surfaces_tint_shadows_with_their_pattern() {
+        let lines = [
+            "bulb 0 4 0",
+            "color 1 1 1",
+            "checker 1 1 0.5 0 1 0.5 0",
+            "transparency 0.5",
+            "plane 0 1 0 -2",
+            "texture none",
+            "transparency 0",
+            "plane 0 1 0 0",
+        ];
+        let file = ProcFile {
+            header: FileHeader::from_str("png 10 10 shadows.png").unwrap(),
+            entries: lines
+                .iter()
+                .map(|l| FileEntry::from_str(l).unwrap())
+                .collect(),
+        };
+        let scene = Scene::from_file(&file).unwrap();
+        let lighting_model = LightingModel::from_file(&file, &scene);
+        let ray = Ray::new(Point3::new(0.0, 1.0, 0.0), Vector3::new(0.0, -1.0, 0.0));
+        let hit = lighting_model.ray_tracer.trace_ray(&ray).unwrap();
+        let light = lighting_model.light(&hit);
+        assert!((light - Vector3::new(0.5, 0.25, 0.0).scale(1.0 / 16.0)).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn shadows_through_a_medium_are_dimmed_by_the_distance_inside_it() {
+        let lines = [
+            "bulb 0 4 0",
+            "medium 1 1 1 1",
+            "sphere 0 2 0 0.5",
+            "medium none",
+            "plane 0 1 0 0",
+        ];
+        let file = ProcFile {
+            header: FileHeader::from_str("png 10 10 medium.png").unwrap(),
+            entries: lines
+                .iter()
+                .map(|l| FileEntry::from_str(l).unwrap())
+                .collect(),
+        };
+        let scene = Scene::from_file(&file).unwrap();
+        let lighting_model = LightingModel::from_file(&file, &scene);
+        let ray = Ray::new(Point3::new(0.5, 1.0, 0.0), Vector3::new(-0.5, -1.0, 0.0));
+        let hit = lighting_model.ray_tracer.trace_ray(&ray).unwrap();
+        let light = lighting_model.light(&hit);
+        assert!((light.x - (-1.0f64).exp() / 16.0).abs() < 1e-9);
+    }
 }
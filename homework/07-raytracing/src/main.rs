@@ -1,11 +1,26 @@
+mod blob;
+mod csg;
+mod fractals;
+mod intersections;
 mod lighting_models;
+mod medium;
+mod mesh;
 mod models;
 mod parser;
+mod pattern;
+mod photons;
 mod rasterize;
 mod raytracer;
 mod renderer;
 mod scene;
+mod sdf;
+mod solvers;
+mod subdivision;
+mod terrain;
+mod texture;
+mod transform;
 mod utils;
+mod volume;
 
 use crate::parser::{parse_file, ProcFile};
 use crate::rasterize::Rasterizer;
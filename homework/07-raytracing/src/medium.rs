@@ -0,0 +1,78 @@
+use nalgebra::Vector3;
+
+// Homogeneous participating medium. `density` is the fraction of light taken out of a ray per
+// unit of distance, and `color` the part of that which is scattered rather than absorbed.
+#[derive(Debug, Clone, Copy)]
+pub struct Medium {
+    pub density: f64,
+    pub color: Vector3<f64>,
+}
+
+impl Medium {
+    // Beer-Lambert law
+    pub fn transmittance(&self, distance: f64) -> f64 {
+        (-self.density * distance).exp()
+    }
+
+    // distance at which the transmittance falls to `t`
+    pub fn distance_to(&self, t: f64) -> f64 {
+        -t.ln() / self.density
+    }
+
+    // two media filling the same space, such as fog around a cloud
+    pub fn add(&self, other: &Medium) -> Medium {
+        let density = self.density + other.density;
+        Medium {
+            density,
+            color: (self.color.scale(self.density) + other.color.scale(other.density))
+                .scale(1.0 / density),
+        }
+    }
+}
+
+// what fills the object a ray is inside of
+#[derive(Debug, Clone, Copy)]
+pub enum Interior {
+    Medium(Medium),
+    // index into the scene's volumes
+    Volume(usize),
+}
+
+// the medium filling the space a ray travels through, if any
+pub fn combine(a: Option<Medium>, b: Option<Medium>) -> Option<Medium> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.add(&b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod medium_tests {
+    use super::*;
+
+    #[test]
+    fn transmittance_halves_over_the_half_distance() {
+        let m = Medium {
+            density: 0.5,
+            color: Vector3::new(1.0, 1.0, 1.0),
+        };
+        let half = m.distance_to(0.5);
+        assert!((m.transmittance(half) - 0.5).abs() < 1e-12);
+        assert!((m.transmittance(2.0 * half) - 0.25).abs() < 1e-12);
+    }
+
+    #[test]
+    fn added_media_weigh_colors_by_density() {
+        let red = Medium {
+            density: 3.0,
+            color: Vector3::new(1.0, 0.0, 0.0),
+        };
+        let blue = Medium {
+            density: 1.0,
+            color: Vector3::new(0.0, 0.0, 1.0),
+        };
+        let m = combine(Some(red), Some(blue)).unwrap();
+        assert_eq!(4.0, m.density);
+        assert_eq!(Vector3::new(0.75, 0.0, 0.25), m.color);
+    }
+}
@@ -0,0 +1,531 @@
+use crate::models::{Material, ObjPrimative, SceneObject};
+use crate::subdivision::{catmull_clark, loop_subdivide};
+use crate::texture::{Displacement, Texture};
+use crate::transform::Transform;
+use nalgebra::{Point3, Vector2, Vector3};
+use std::collections::{HashMap, HashSet};
+use std::ops::Sub;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeshOptions {
+    // maximum angle (in degrees) between two faces that will still be smoothed together
+    pub smooth: Option<f64>,
+    // distance under which two vertices are treated as the same vertex
+    pub weld: Option<f64>,
+    // number of times the mesh is refined into a smooth surface, with loop subdivision for
+    // triangle meshes and catmull-clark once there are any quads
+    pub subdivide: Option<usize>,
+}
+
+pub const DEFAULT_MESH_OPTIONS: MeshOptions = MeshOptions {
+    smooth: None,
+    weld: None,
+    subdivide: None,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct MeshFace {
+    pub indices: [usize; 3],
+    pub material: Material,
+}
+
+// corners are given counter-clockwise, and only stay a quad until the mesh is subdivided
+#[derive(Debug, Clone, Copy)]
+pub struct MeshQuad {
+    pub indices: [usize; 4],
+    pub material: Material,
+}
+
+// A run of triangles and quads read from `trif` and `quadf` entries which is preprocessed as a whole before being
+// turned into scene objects
+#[derive(Debug, Clone)]
+pub struct Mesh {
+    pub vertices: Vec<Point3<f64>>,
+    pub uvs: Vec<Vector2<f64>>,
+    pub faces: Vec<MeshFace>,
+    pub quads: Vec<MeshQuad>,
+    // edges kept sharp by subdivision, as pairs of vertex indices with the smaller one first
+    pub creases: HashSet<(usize, usize)>,
+    // applied to vertices as they are added to the mesh
+    pub transform: Transform,
+    // maps indices into the scene's vertex list onto indices into `vertices`
+    vertex_map: HashMap<usize, usize>,
+}
+
+impl Mesh {
+    pub fn new() -> Self {
+        Self {
+            vertices: vec![],
+            uvs: vec![],
+            faces: vec![],
+            quads: vec![],
+            creases: HashSet::new(),
+            transform: Transform::identity(),
+            vertex_map: HashMap::new(),
+        }
+    }
+
+    // returns the faces gathered so far, leaving an empty mesh with the same transform behind
+    pub fn take(&mut self) -> Mesh {
+        let mut empty = Mesh::new();
+        empty.transform = self.transform;
+        std::mem::replace(self, empty)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.faces.is_empty() && self.quads.is_empty()
+    }
+
+    fn add_vertex(
+        &mut self,
+        index: usize,
+        texcoord: Option<usize>,
+        scene_vertices: &[Point3<f64>],
+        scene_texcoords: &[Vector2<f64>],
+    ) -> usize {
+        match self.vertex_map.get(&index) {
+            Some(&i) => i,
+            None => {
+                self.vertices
+                    .push(self.transform.point(&scene_vertices[index]));
+                self.uvs
+                    .push(match texcoord.and_then(|t| scene_texcoords.get(t)) {
+                        Some(uv) => *uv,
+                        None => Vector2::zeros(),
+                    });
+                self.vertex_map.insert(index, self.vertices.len() - 1);
+                self.vertices.len() - 1
+            }
+        }
+    }
+
+    // Adds a face given indices into the scene's vertex list, along with the index of each
+    // corner's texture coordinate if it has one. A vertex keeps the texture coordinate of the
+    // first face it is added with.
+    pub fn add_face(
+        &mut self,
+        indices: [usize; 3],
+        texcoords: [Option<usize>; 3],
+        scene_vertices: &[Point3<f64>],
+        scene_texcoords: &[Vector2<f64>],
+        material: Material,
+    ) {
+        let indices = std::array::from_fn(|i| {
+            self.add_vertex(indices[i], texcoords[i], scene_vertices, scene_texcoords)
+        });
+        self.faces.push(MeshFace { indices, material });
+    }
+
+    pub fn add_quad(
+        &mut self,
+        indices: [usize; 4],
+        texcoords: [Option<usize>; 4],
+        scene_vertices: &[Point3<f64>],
+        scene_texcoords: &[Vector2<f64>],
+        material: Material,
+    ) {
+        let indices = std::array::from_fn(|i| {
+            self.add_vertex(indices[i], texcoords[i], scene_vertices, scene_texcoords)
+        });
+        self.quads.push(MeshQuad { indices, material });
+    }
+
+    // marks the edge between two of the scene's vertices as sharp
+    pub fn add_crease(
+        &mut self,
+        [a, b]: [usize; 2],
+        texcoords: [Option<usize>; 2],
+        scene_vertices: &[Point3<f64>],
+        scene_texcoords: &[Vector2<f64>],
+    ) {
+        let a = self.add_vertex(a, texcoords[0], scene_vertices, scene_texcoords);
+        let b = self.add_vertex(b, texcoords[1], scene_vertices, scene_texcoords);
+        self.creases.insert((a.min(b), a.max(b)));
+    }
+
+    // Merges every vertex lying within `tolerance` of an earlier vertex with the same texture
+    // coordinate into that vertex. Faces which collapse as a result are removed.
+    pub fn weld(&mut self, tolerance: f64) {
+        let cell = |p: &Point3<f64>| p.map(|c| (c / tolerance).floor() as i64);
+        let mut grid: HashMap<Point3<i64>, Vec<usize>> = HashMap::new();
+        let mut vertices: Vec<Point3<f64>> = vec![];
+        let mut uvs: Vec<Vector2<f64>> = vec![];
+        let mut remap: Vec<usize> = vec![];
+        for (v, uv) in self.vertices.iter().zip(&self.uvs) {
+            let c = cell(v);
+            let mut found: Option<usize> = None;
+            'search: for dx in -1..=1 {
+                for dy in -1..=1 {
+                    for dz in -1..=1 {
+                        let neighbour = Point3::new(c.x + dx, c.y + dy, c.z + dz);
+                        if let Some(candidates) = grid.get(&neighbour) {
+                            if let Some(&i) = candidates.iter().find(|&&i| {
+                                (vertices[i] - v).magnitude() <= tolerance
+                                    && (uvs[i] - uv).magnitude() <= tolerance
+                            }) {
+                                found = Some(i);
+                                break 'search;
+                            }
+                        }
+                    }
+                }
+            }
+            let index = match found {
+                Some(i) => i,
+                None => {
+                    vertices.push(*v);
+                    uvs.push(*uv);
+                    grid.entry(c).or_default().push(vertices.len() - 1);
+                    vertices.len() - 1
+                }
+            };
+            remap.push(index);
+        }
+        self.faces = self
+            .faces
+            .iter()
+            .map(|f| MeshFace {
+                indices: f.indices.map(|i| remap[i]),
+                material: f.material,
+            })
+            .filter(|f| {
+                let [a, b, c] = f.indices;
+                a != b && b != c && a != c
+            })
+            .collect();
+        self.quads = self
+            .quads
+            .iter()
+            .map(|q| MeshQuad {
+                indices: q.indices.map(|i| remap[i]),
+                material: q.material,
+            })
+            .filter(|q| (0..4).all(|i| q.indices[i] != q.indices[(i + 1) % 4]))
+            .collect();
+        self.creases = self
+            .creases
+            .iter()
+            .map(|&(a, b)| (remap[a].min(remap[b]), remap[a].max(remap[b])))
+            .collect();
+        self.vertices = vertices;
+        self.uvs = uvs;
+    }
+
+    // area weighted normal of a face (its magnitude is twice the face's area)
+    fn face_normal(&self, face: &MeshFace) -> Vector3<f64> {
+        let [a, b, c] = face.indices.map(|i| self.vertices[i]);
+        b.sub(a).cross(&c.sub(b))
+    }
+
+    // Computes a normal for every corner of every face by averaging the normals of the faces
+    // around that corner's vertex. Faces meeting at more than `angle` degrees are left out so
+    // that hard edges stay hard.
+    pub fn vertex_normals(&self, angle: f64) -> Vec<[Vector3<f64>; 3]> {
+        let face_normals: Vec<Vector3<f64>> =
+            self.faces.iter().map(|f| self.face_normal(f)).collect();
+        let mut incident: Vec<Vec<usize>> = vec![vec![]; self.vertices.len()];
+        for (i, face) in self.faces.iter().enumerate() {
+            for &v in &face.indices {
+                incident[v].push(i);
+            }
+        }
+        let cos_threshold = angle.to_radians().cos();
+        self.faces
+            .iter()
+            .enumerate()
+            .map(|(i, face)| {
+                let n = face_normals[i].normalize();
+                face.indices.map(|v| {
+                    let sum: Vector3<f64> = incident[v]
+                        .iter()
+                        .map(|&j| face_normals[j])
+                        .filter(|m| m.normalize().dot(&n) >= cos_threshold)
+                        .sum();
+                    match sum.try_normalize(f64::EPSILON) {
+                        Some(s) => s,
+                        None => n,
+                    }
+                })
+            })
+            .collect()
+    }
+
+    // splits every face into four by adding a vertex in the middle of each edge, which faces
+    // sharing the edge also share
+    fn split_faces(&mut self) {
+        let mut midpoints: HashMap<(usize, usize), usize> = HashMap::new();
+        let mut faces = vec![];
+        for face in &self.faces {
+            let [a, b, c] = face.indices;
+            let mut midpoint = |i: usize, j: usize| {
+                *midpoints.entry((i.min(j), i.max(j))).or_insert_with(|| {
+                    let p = self.vertices[i] + self.vertices[j].sub(self.vertices[i]).scale(0.5);
+                    self.vertices.push(p);
+                    self.uvs.push((self.uvs[i] + self.uvs[j]).scale(0.5));
+                    self.vertices.len() - 1
+                })
+            };
+            let (ab, bc, ca) = (midpoint(a, b), midpoint(b, c), midpoint(c, a));
+            for indices in [[a, ab, ca], [ab, b, bc], [ca, bc, c], [ab, bc, ca]] {
+                faces.push(MeshFace {
+                    indices,
+                    material: face.material,
+                });
+            }
+        }
+        self.faces = faces;
+    }
+
+    // Subdivides the mesh as often as its most detailed displacement asks for, then moves each
+    // vertex along its smoothed normal. Vertices take the displacement of the first face using
+    // them, and are left in place if it has none.
+    pub fn displace(&mut self, textures: &[Texture]) {
+        let levels = self
+            .faces
+            .iter()
+            .filter_map(|f| f.material.displacement)
+            .map(|d| d.levels)
+            .max();
+        let levels = match levels {
+            Some(l) => l,
+            None => return,
+        };
+        for _ in 0..levels {
+            self.split_faces();
+        }
+        let mut normals = vec![Vector3::zeros(); self.vertices.len()];
+        let mut displacements: Vec<Option<Displacement>> = vec![None; self.vertices.len()];
+        for face in &self.faces {
+            let n = self.face_normal(face);
+            for &v in &face.indices {
+                normals[v] += n;
+                displacements[v] = displacements[v].or(face.material.displacement);
+            }
+        }
+        for (v, displacement) in displacements.iter().enumerate() {
+            if let (Some(d), Some(n)) = (displacement, normals[v].try_normalize(f64::EPSILON)) {
+                let offset = d.offset(&self.vertices[v], &self.uvs[v], textures);
+                self.vertices[v] += n.scale(offset);
+            }
+        }
+    }
+
+    // refines the mesh into a smooth surface, leaving only triangles behind
+    pub fn subdivide(&mut self, levels: usize) {
+        for _ in 0..levels {
+            match self.quads.is_empty() {
+                true => loop_subdivide(self),
+                false => catmull_clark(self),
+            }
+        }
+        self.triangulate_quads();
+    }
+
+    fn triangulate_quads(&mut self) {
+        for quad in std::mem::take(&mut self.quads) {
+            let [a, b, c, d] = quad.indices;
+            for indices in [[a, b, c], [a, c, d]] {
+                self.faces.push(MeshFace {
+                    indices,
+                    material: quad.material,
+                });
+            }
+        }
+    }
+
+    // normals are worked out last so that they follow the subdivided and displaced surface
+    pub fn into_objects(mut self, options: &MeshOptions, textures: &[Texture]) -> Vec<SceneObject> {
+        if let Some(tolerance) = options.weld {
+            self.weld(tolerance);
+        }
+        self.subdivide(options.subdivide.unwrap_or(0));
+        self.displace(textures);
+        let normals: Option<Vec<[Vector3<f64>; 3]>> =
+            options.smooth.map(|a| self.vertex_normals(a));
+        self.faces
+            .iter()
+            .enumerate()
+            .map(|(i, face)| {
+                let vertices = face.indices.map(|v| self.vertices[v]);
+                let uvs = face.indices.map(|v| self.uvs[v]);
+                let primitive =
+                    ObjPrimative::triangle(vertices, normals.as_ref().map(|n| n[i]), uvs);
+                SceneObject::new(primitive, face.material)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod mesh_tests {
+    use super::*;
+    use crate::models::DEFAULT_MATERIAL;
+
+    // two triangles of a unit square given as triangle soup
+    fn square_soup() -> Mesh {
+        let vertices = vec![
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(1.0, 1.0, 0.0),
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 1.00001, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+        ];
+        let mut mesh = Mesh::new();
+        mesh.add_face([0, 1, 2], [None; 3], &vertices, &[], DEFAULT_MATERIAL);
+        mesh.add_face([3, 4, 5], [None; 3], &vertices, &[], DEFAULT_MATERIAL);
+        mesh
+    }
+
+    #[test]
+    fn weld_merges_vertices_within_tolerance() {
+        let mut mesh = square_soup();
+        mesh.weld(0.001);
+        assert_eq!(4, mesh.vertices.len());
+        assert_eq!(mesh.faces[0].indices[0], mesh.faces[1].indices[0]);
+        assert_eq!(mesh.faces[0].indices[2], mesh.faces[1].indices[1]);
+    }
+
+    #[test]
+    fn weld_keeps_vertices_outside_tolerance() {
+        let mut mesh = square_soup();
+        mesh.weld(0.000001);
+        assert_eq!(5, mesh.vertices.len());
+    }
+
+    #[test]
+    fn weld_removes_collapsed_faces() {
+        let vertices = vec![
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(0.0001, 0.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+        ];
+        let mut mesh = Mesh::new();
+        mesh.add_face([0, 1, 2], [None; 3], &vertices, &[], DEFAULT_MATERIAL);
+        mesh.weld(0.001);
+        assert!(mesh.is_empty());
+    }
+
+    #[test]
+    fn vertex_normals_keep_hard_edges() {
+        // two faces folded at a right angle along the x axis
+        let vertices = vec![
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+            Point3::new(0.0, 0.0, 1.0),
+        ];
+        let mut mesh = Mesh::new();
+        mesh.add_face([0, 1, 2], [None; 3], &vertices, &[], DEFAULT_MATERIAL);
+        mesh.add_face([1, 0, 3], [None; 3], &vertices, &[], DEFAULT_MATERIAL);
+
+        let hard = mesh.vertex_normals(45.0);
+        assert!((hard[0][0] - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1e-9);
+
+        let smooth = mesh.vertex_normals(120.0);
+        let expected = Vector3::new(0.0, 1.0, 1.0).normalize();
+        assert!((smooth[0][0] - expected).magnitude() < 1e-9);
+        assert!((smooth[1][1] - expected).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn displace_subdivides_and_moves_vertices_along_normals() {
+        let vertices = vec![
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+        ];
+        let textures = vec![Texture {
+            width: 1,
+            height: 1,
+            texels: vec![Vector3::new(1.0, 1.0, 1.0)],
+            wrap: crate::texture::WrapMode::Repeat,
+        }];
+        let material = Material {
+            displacement: Some(Displacement {
+                source: crate::texture::DisplacementSource::Texture(0),
+                amount: 0.5,
+                levels: 2,
+            }),
+            ..DEFAULT_MATERIAL
+        };
+        let mut mesh = Mesh::new();
+        mesh.add_face([0, 1, 2], [None; 3], &vertices, &[], material);
+        mesh.displace(&textures);
+        assert_eq!(16, mesh.faces.len());
+        // every edge midpoint is shared, so there are 15 vertices rather than 48
+        assert_eq!(15, mesh.vertices.len());
+        assert!(mesh.vertices.iter().all(|v| (v.z - 0.5).abs() < 1e-9));
+    }
+
+    // a unit cube made of six quads
+    fn cube() -> Mesh {
+        let vertices: Vec<Point3<f64>> = (0..8)
+            .map(|i| Point3::new((i & 1) as f64, ((i >> 1) & 1) as f64, (i >> 2) as f64))
+            .collect();
+        let mut mesh = Mesh::new();
+        let faces = [
+            [0, 2, 3, 1],
+            [4, 5, 7, 6],
+            [0, 1, 5, 4],
+            [2, 6, 7, 3],
+            [0, 4, 6, 2],
+            [1, 3, 7, 5],
+        ];
+        for face in faces {
+            mesh.add_quad(face, [None; 4], &vertices, &[], DEFAULT_MATERIAL);
+        }
+        mesh
+    }
+
+    #[test]
+    fn catmull_clark_rounds_off_a_cube() {
+        let mut mesh = cube();
+        mesh.subdivide(2);
+        // each level turns every quad into four
+        assert_eq!(6 * 16 * 2, mesh.faces.len());
+        let center = Point3::new(0.5, 0.5, 0.5);
+        let distances: Vec<f64> = mesh
+            .vertices
+            .iter()
+            .map(|v| (v - center).magnitude())
+            .collect();
+        let corner = (3.0f64).sqrt() / 2.0;
+        assert!(distances.iter().all(|&d| d < corner - 0.1));
+    }
+
+    #[test]
+    fn creases_keep_their_edges_straight() {
+        let vertices: Vec<Point3<f64>> = (0..8)
+            .map(|i| Point3::new((i & 1) as f64, ((i >> 1) & 1) as f64, (i >> 2) as f64))
+            .collect();
+        let mut mesh = cube();
+        // every edge of the bottom face
+        for (a, b) in [(0, 1), (1, 3), (3, 2), (2, 0)] {
+            mesh.add_crease([a, b], [None; 2], &vertices, &[]);
+        }
+        mesh.subdivide(1);
+        // the bottom corners stay on the bottom face
+        assert!(mesh.vertices[..4].iter().all(|v| v.z == 0.0));
+        assert!(mesh.vertices[4..8].iter().all(|v| v.z < 1.0));
+    }
+
+    #[test]
+    fn loop_subdivision_shrinks_a_tetrahedron_towards_its_center() {
+        let vertices = vec![
+            Point3::new(1.0, 1.0, 1.0),
+            Point3::new(1.0, -1.0, -1.0),
+            Point3::new(-1.0, 1.0, -1.0),
+            Point3::new(-1.0, -1.0, 1.0),
+        ];
+        let mut mesh = Mesh::new();
+        for face in [[0, 1, 2], [0, 3, 1], [0, 2, 3], [1, 3, 2]] {
+            mesh.add_face(face, [None; 3], &vertices, &[], DEFAULT_MATERIAL);
+        }
+        mesh.subdivide(1);
+        assert_eq!(16, mesh.faces.len());
+        assert_eq!(10, mesh.vertices.len());
+        // valence 3 corners keep 1 - 3 * 3 / 16 of their position, the rest comes from the others
+        assert!((mesh.vertices[0].coords - Vector3::new(0.25, 0.25, 0.25)).magnitude() < 1e-9);
+    }
+}
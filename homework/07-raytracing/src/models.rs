@@ -1,5 +1,9 @@
-use nalgebra::{Point3, Vector3};
-use std::ops::Div;
+use crate::medium::Medium;
+use crate::pattern::{Pattern, PatternSpace};
+use crate::texture::{BumpMap, Displacement};
+use crate::transform::Transform;
+use nalgebra::{Point3, Vector2, Vector3};
+use std::ops::{Div, Sub};
 use uuid::Uuid;
 
 #[derive(Copy, Clone, Debug)]
@@ -22,6 +26,10 @@ impl AABB {
             && self.max.z > other.min.z
     }
 
+    pub fn union(&self, other: &AABB) -> AABB {
+        AABB::new(self.min.inf(&other.min), self.max.sup(&other.max))
+    }
+
     // This function created by chatGPT and modified heavily to save a bit of time and annoying coding
     // turns out that it didn't same me much time. Maybe chatGPT 5 will do better, but I'm not sure.
     pub fn subdivide(&self) -> Vec<AABB> {
@@ -44,6 +52,12 @@ impl AABB {
     }
 }
 
+// box around a disk of radius `r`, which reaches less far along axes close to its normal
+fn disk_aabb(center: &Point3<f64>, n: &Vector3<f64>, r: f64) -> AABB {
+    let extent = n.map(|c| r * (1.0 - c * c).max(0.0).sqrt());
+    AABB::new(center - extent, center + extent)
+}
+
 #[cfg(test)]
 mod aabb_tests {
     use super::*;
@@ -73,6 +87,8 @@ mod aabb_tests {
     }
 }
 
+// triangles carry their shading data inline so that objects stay `Copy`
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Copy)]
 pub enum ObjPrimative {
     Sphere {
@@ -86,15 +102,112 @@ pub enum ObjPrimative {
     Triangle {
         vertices: [Point3<f64>; 3],
         n: Vector3<f64>,
-        e1: Vector3<f64>,
-        e2: Vector3<f64>,
+        // per vertex normals used for smooth shading
+        normals: Option<[Vector3<f64>; 3]>,
+        uvs: [Vector2<f64>; 3],
+    },
+    // runs `height` along the unit `axis` from the center of its base
+    Cylinder {
+        base: Point3<f64>,
+        axis: Vector3<f64>,
+        height: f64,
+        r: f64,
+        capped: bool,
+    },
+    // base of radius `r` narrowing to a point `height` along the unit `axis`
+    Cone {
+        base: Point3<f64>,
+        axis: Vector3<f64>,
+        height: f64,
+        r: f64,
+        capped: bool,
+    },
+    Disk {
+        center: Point3<f64>,
+        n: Vector3<f64>,
+        r: f64,
+    },
+    Box {
+        min: Point3<f64>,
+        max: Point3<f64>,
+    },
+    // tube of radius `minor` swept around the unit `axis` at a distance of `major` from `center`
+    Torus {
+        center: Point3<f64>,
+        axis: Vector3<f64>,
+        major: f64,
+        minor: f64,
+    },
+    // coefficients A to J of A x^2 + B y^2 + C z^2 + D xy + E xz + F yz + G x + H y + I z + J = 0
+    Quadric {
+        coefficients: [f64; 10],
+    },
+    // two solids combined by one of the scene's csg nodes
+    Csg {
+        node: usize,
+        bounds: Option<AABB>,
+    },
+    // one of the scene's signed distance shapes, which are already in world space
+    Sdf {
+        sdf: usize,
+        bounds: AABB,
+    },
+    // one of the scene's blobs, given in object space
+    Blob {
+        blob: usize,
+        bounds: AABB,
     },
+    // box filled by one of the scene's volumes
+    Volume {
+        volume: usize,
+        min: Point3<f64>,
+        max: Point3<f64>,
+    },
+    // a placement of one of the scene's shared definitions
+    Instance {
+        definition: usize,
+        // bounds of the definition in its own space, if all of its objects are bounded
+        bounds: Option<AABB>,
+        // whether the objects of the definition are drawn in the instance's material
+        override_material: bool,
+    },
+}
+
+impl ObjPrimative {
+    pub fn triangle(
+        vertices: [Point3<f64>; 3],
+        normals: Option<[Vector3<f64>; 3]>,
+        uvs: [Vector2<f64>; 3],
+    ) -> Self {
+        let n = vertices[1]
+            .sub(vertices[0])
+            .cross(&vertices[2].sub(vertices[1]))
+            .normalize();
+        ObjPrimative::Triangle {
+            vertices,
+            n,
+            normals,
+            uvs,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct Material {
     pub color: Vector3<f64>,
     pub shininess: f64,
+    // index into the scene's textures, used in place of `color` when set
+    pub texture: Option<usize>,
+    pub pattern: Option<Pattern>,
+    pub bump: Option<BumpMap>,
+    // only applies to triangle meshes, which are displaced as they are built
+    pub displacement: Option<Displacement>,
+    // when set, the surface only bounds the medium inside of it and is otherwise invisible
+    pub medium: Option<Medium>,
+    // fraction of light which passes straight through the surface, tinted by its color
+    pub transparency: f64,
+    // one sided surfaces are invisible from behind, where the ray meets their back face
+    pub two_sided: bool,
 }
 
 pub const DEFAULT_COLOR: Vector3<f64> = Vector3::new(1.0, 1.0, 1.0);
@@ -102,6 +215,13 @@ pub const DEFAULT_SHININESS: f64 = 0.0;
 pub const DEFAULT_MATERIAL: Material = Material {
     color: DEFAULT_COLOR,
     shininess: DEFAULT_SHININESS,
+    texture: None,
+    pattern: None,
+    bump: None,
+    displacement: None,
+    medium: None,
+    transparency: 0.0,
+    two_sided: true,
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -110,6 +230,8 @@ pub struct SceneObject {
     pub primitive: ObjPrimative,
     pub material: Material,
     pub aabb: Option<AABB>,
+    // when set, the primitive is given in object space and rays are moved into it to intersect
+    pub transform: Option<Transform>,
 }
 
 impl SceneObject {
@@ -118,12 +240,7 @@ impl SceneObject {
             ObjPrimative::Sphere { xyz, r } => {
                 Some(AABB::new(xyz.map(|i| i - r), xyz.map(|i| i + r)))
             }
-            ObjPrimative::Triangle {
-                vertices,
-                n: _,
-                e1: _,
-                e2: _,
-            } => {
+            ObjPrimative::Triangle { vertices, .. } => {
                 let min_x = vertices.iter().fold(f64::INFINITY, |a, &b| a.min(b.x));
                 let min_y = vertices.iter().fold(f64::INFINITY, |a, &b| a.min(b.y));
                 let min_z = vertices.iter().fold(f64::INFINITY, |a, &b| a.min(b.z));
@@ -135,6 +252,44 @@ impl SceneObject {
                     Point3::new(max_x, max_y, max_z),
                 ))
             }
+            ObjPrimative::Cylinder {
+                base,
+                axis,
+                height,
+                r,
+                ..
+            } => {
+                let top = base + axis.scale(height);
+                Some(disk_aabb(&base, &axis, r).union(&disk_aabb(&top, &axis, r)))
+            }
+            ObjPrimative::Cone {
+                base,
+                axis,
+                height,
+                r,
+                ..
+            } => {
+                let apex = base + axis.scale(height);
+                Some(disk_aabb(&base, &axis, r).union(&AABB::new(apex, apex)))
+            }
+            ObjPrimative::Disk { center, n, r } => Some(disk_aabb(&center, &n, r)),
+            ObjPrimative::Box { min, max } | ObjPrimative::Volume { min, max, .. } => {
+                Some(AABB::new(min, max))
+            }
+            ObjPrimative::Torus {
+                center,
+                axis,
+                major,
+                minor,
+            } => {
+                let ring = disk_aabb(&center, &axis, major);
+                let tube = Vector3::new(minor, minor, minor);
+                Some(AABB::new(ring.min - tube, ring.max + tube))
+            }
+            ObjPrimative::Instance { bounds, .. } => bounds,
+            ObjPrimative::Csg { bounds, .. } => bounds,
+            ObjPrimative::Sdf { bounds, .. } => Some(bounds),
+            ObjPrimative::Blob { bounds, .. } => Some(bounds),
             _ => None,
         };
         Self {
@@ -142,6 +297,70 @@ impl SceneObject {
             primitive: primative,
             material,
             aabb,
+            transform: None,
+        }
+    }
+
+    pub fn transformed(primative: ObjPrimative, material: Material, transform: Transform) -> Self {
+        let object = Self::new(primative, material);
+        match transform.is_identity() {
+            true => object,
+            false => Self {
+                aabb: object.aabb.map(|aabb| transform.aabb(&aabb)),
+                transform: Some(transform),
+                ..object
+            },
+        }
+    }
+
+    // whether the primitive encloses a volume, which csg needs to tell inside from outside.
+    // Cylinders and cones without caps are open tubes, which a ray can cross only once. Sdfs
+    // and blobs are only searched for their first crossing, so they can not list every
+    // crossing either.
+    pub fn is_solid(&self) -> bool {
+        match self.primitive {
+            ObjPrimative::Cylinder { capped, .. } | ObjPrimative::Cone { capped, .. } => capped,
+            ObjPrimative::Triangle { .. }
+            | ObjPrimative::Disk { .. }
+            | ObjPrimative::Sdf { .. }
+            | ObjPrimative::Blob { .. }
+            | ObjPrimative::Instance { .. } => false,
+            _ => true,
+        }
+    }
+
+    // moves a point into the object's own coordinate system, which has its origin at the
+    // object's center (or first vertex)
+    pub fn object_space_position(&self, p: &Point3<f64>) -> Point3<f64> {
+        let p = match self.transform {
+            Some(t) => t.inverse_point(p),
+            None => *p,
+        };
+        let origin = match self.primitive {
+            ObjPrimative::Sphere { xyz, .. } => xyz,
+            ObjPrimative::Plane { p, .. } => p,
+            ObjPrimative::Triangle { vertices, .. } => vertices[0],
+            ObjPrimative::Cylinder { base, .. } => base,
+            ObjPrimative::Cone { base, .. } => base,
+            ObjPrimative::Disk { center, .. } => center,
+            ObjPrimative::Box { min, max } | ObjPrimative::Volume { min, max, .. } => {
+                min + max.sub(min).scale(0.5)
+            }
+            ObjPrimative::Torus { center, .. } => center,
+            ObjPrimative::Quadric { .. } => Point3::origin(),
+            ObjPrimative::Instance { .. } => Point3::origin(),
+            ObjPrimative::Csg { .. } => Point3::origin(),
+            ObjPrimative::Sdf { .. } => Point3::origin(),
+            ObjPrimative::Blob { .. } => Point3::origin(),
+        };
+        Point3::origin() + p.sub(origin)
+    }
+
+    // position at which the material's pattern should be evaluated
+    pub fn pattern_position(&self, pattern: &Pattern, p: &Point3<f64>) -> Point3<f64> {
+        match pattern.space {
+            PatternSpace::World => *p,
+            PatternSpace::Object => self.object_space_position(p),
         }
     }
 }
@@ -152,14 +371,84 @@ pub enum LightPrimitive {
     Point(Point3<f64>),
 }
 
+// how the light from a bulb dims with distance
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Falloff {
+    None,
+    Linear,
+    Quadratic,
+    // fades smoothly from full brightness at the bulb to nothing at the given distance
+    Range(f64),
+}
+
+impl Falloff {
+    // fraction of a bulb's intensity which reaches `distance` away from it
+    pub fn attenuation(&self, distance: f64) -> f64 {
+        match self {
+            Falloff::None => 1.0,
+            Falloff::Linear => 1.0 / distance,
+            Falloff::Quadratic => 1.0 / (distance * distance),
+            Falloff::Range(r) => (1.0 - (distance / r).powi(2)).max(0.0).powi(2),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct LightSourceObject {
     pub source: LightPrimitive,
     pub color: Vector3<f64>,
+    pub intensity: f64,
+    // only used by bulbs, as suns are infinitely far away
+    pub falloff: Falloff,
 }
 
 impl LightSourceObject {
-    pub fn new(source: LightPrimitive, color: Vector3<f64>) -> Self {
-        Self { source, color }
+    pub fn new(
+        source: LightPrimitive,
+        color: Vector3<f64>,
+        intensity: f64,
+        falloff: Falloff,
+    ) -> Self {
+        Self {
+            source,
+            color,
+            intensity,
+            falloff,
+        }
+    }
+
+    // color of the light `distance` away from it
+    pub fn brightness(&self, distance: f64) -> Vector3<f64> {
+        match self.source {
+            LightPrimitive::Directional(_) => self.color.scale(self.intensity),
+            LightPrimitive::Point(_) => self
+                .color
+                .scale(self.intensity * self.falloff.attenuation(distance)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod light_tests {
+    use super::*;
+
+    #[test]
+    fn falloff_attenuates_bulbs_but_not_suns() {
+        let white = Vector3::new(1.0, 1.0, 1.0);
+        let bulb = |falloff| {
+            LightSourceObject::new(LightPrimitive::Point(Point3::origin()), white, 2.0, falloff)
+        };
+        assert_eq!(2.0, bulb(Falloff::None).brightness(4.0).x);
+        assert_eq!(0.5, bulb(Falloff::Linear).brightness(4.0).x);
+        assert_eq!(0.125, bulb(Falloff::Quadratic).brightness(4.0).x);
+        assert_eq!(2.0, bulb(Falloff::Range(4.0)).brightness(0.0).x);
+        assert_eq!(0.0, bulb(Falloff::Range(4.0)).brightness(5.0).x);
+        let sun = LightSourceObject::new(
+            LightPrimitive::Directional(Vector3::new(0.0, 1.0, 0.0)),
+            white,
+            2.0,
+            Falloff::Quadratic,
+        );
+        assert_eq!(2.0, sun.brightness(f64::INFINITY).x);
     }
 }
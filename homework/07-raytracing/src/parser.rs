@@ -1,6 +1,18 @@
+use crate::csg::CsgOperation;
+use crate::models::Falloff;
+use crate::pattern::{PatternKind, PatternSpace};
+use crate::texture::WrapMode;
 use std::path::PathBuf;
 use std::str::FromStr;
 
+// every level of displacement quadruples the number of triangles
+const MAX_DISPLACEMENT_LEVELS: usize = 6;
+// every level of subdivision turns each triangle or quad into four
+const MAX_SUBDIVISION_LEVELS: usize = 6;
+// every level of a fractal multiplies the number of objects by nine for a sphereflake and by
+// twenty for a menger sponge
+const MAX_FRACTAL_DEPTH: usize = 4;
+
 #[derive(Debug)]
 pub enum FileType {
     Png,
@@ -54,20 +66,265 @@ impl FromStr for FileHeader {
 
 #[derive(Debug)]
 pub enum FileEntry {
-    Sphere { x: f64, y: f64, z: f64, r: f64 },
-    Sun { x: f64, y: f64, z: f64 },
-    Color { r: f64, g: f64, b: f64 },
-    Plane { a: f64, b: f64, c: f64, d: f64 },
-    Xyz { x: f64, y: f64, z: f64 },
-    Triangle { a: i32, b: i32, c: i32 },
-    Bulb { x: f64, y: f64, z: f64 },
-    Eye { x: f64, y: f64, z: f64 },
-    Forward { x: f64, y: f64, z: f64 },
-    Up { x: f64, y: f64, z: f64 },
-    Expose { v: f64 },
-    Shiny { s: f64 },
-    Bounces { b: usize },
-    Aa { n: usize },
+    Sphere {
+        x: f64,
+        y: f64,
+        z: f64,
+        r: f64,
+    },
+    Cylinder {
+        a: [f64; 3],
+        b: [f64; 3],
+        r: f64,
+        capped: bool,
+    },
+    Cone {
+        a: [f64; 3],
+        b: [f64; 3],
+        r: f64,
+        capped: bool,
+    },
+    Disk {
+        xyz: [f64; 3],
+        n: [f64; 3],
+        r: f64,
+    },
+    Box {
+        a: [f64; 3],
+        b: [f64; 3],
+    },
+    Torus {
+        xyz: [f64; 3],
+        axis: [f64; 3],
+        major: f64,
+        minor: f64,
+    },
+    Quadric {
+        coefficients: [f64; 10],
+    },
+    Csg {
+        operation: CsgOperation,
+    },
+    Terrain {
+        size: f64,
+        resolution: usize,
+        iterations: usize,
+        seed: u64,
+    },
+    Sphereflake {
+        depth: usize,
+    },
+    Menger {
+        depth: usize,
+    },
+    Blob {
+        threshold: f64,
+    },
+    Ball {
+        xyz: [f64; 3],
+        r: f64,
+        weight: f64,
+    },
+    SdfBox {
+        xyz: [f64; 3],
+        half: [f64; 3],
+        radius: f64,
+    },
+    SdfCapsule {
+        a: [f64; 3],
+        b: [f64; 3],
+        r: f64,
+    },
+    SdfTorus {
+        xyz: [f64; 3],
+        major: f64,
+        minor: f64,
+    },
+    SdfBlend {
+        k: f64,
+    },
+    SdfRepeat {
+        spacing: [f64; 3],
+        count: [usize; 3],
+    },
+    Sun {
+        x: f64,
+        y: f64,
+        z: f64,
+    },
+    Color {
+        r: f64,
+        g: f64,
+        b: f64,
+    },
+    Plane {
+        a: f64,
+        b: f64,
+        c: f64,
+        d: f64,
+    },
+    Xyz {
+        x: f64,
+        y: f64,
+        z: f64,
+    },
+    Triangle {
+        a: i32,
+        b: i32,
+        c: i32,
+    },
+    Quad {
+        a: i32,
+        b: i32,
+        c: i32,
+        d: i32,
+    },
+    Crease {
+        a: i32,
+        b: i32,
+    },
+    Bulb {
+        x: f64,
+        y: f64,
+        z: f64,
+    },
+    // brightness of the lights which follow, which their color is scaled by
+    Intensity {
+        i: f64,
+    },
+    Falloff {
+        falloff: Falloff,
+    },
+    Eye {
+        x: f64,
+        y: f64,
+        z: f64,
+    },
+    Forward {
+        x: f64,
+        y: f64,
+        z: f64,
+    },
+    Up {
+        x: f64,
+        y: f64,
+        z: f64,
+    },
+    Expose {
+        v: f64,
+    },
+    Shiny {
+        s: f64,
+    },
+    Transparency {
+        t: f64,
+    },
+    OneSided,
+    TwoSided,
+    Bounces {
+        b: usize,
+    },
+    Aa {
+        n: usize,
+    },
+    // ambient occlusion from `samples` rays looking `distance` away from each surface seen,
+    // which shades the image on its own when `only` is set
+    Ao {
+        samples: usize,
+        distance: f64,
+        only: bool,
+    },
+    // photons sent from each light, and the radius they are gathered from
+    Photons {
+        count: usize,
+        radius: f64,
+    },
+    // bulbs picked from a light tree to light each hit, instead of every bulb
+    LightSamples {
+        n: usize,
+    },
+    Smooth {
+        angle: f64,
+    },
+    Weld {
+        tolerance: f64,
+    },
+    Subdivide {
+        levels: usize,
+    },
+    Texture {
+        file: Option<String>,
+        wrap: WrapMode,
+    },
+    Texcoord {
+        u: f64,
+        v: f64,
+    },
+    Translate {
+        x: f64,
+        y: f64,
+        z: f64,
+    },
+    Rotate {
+        x: f64,
+        y: f64,
+        z: f64,
+        degrees: f64,
+    },
+    Scale {
+        x: f64,
+        y: f64,
+        z: f64,
+    },
+    Push,
+    Pop,
+    Define {
+        name: String,
+    },
+    End,
+    // `material` draws the instance in the current material instead of the definition's own
+    Instance {
+        name: String,
+        material: bool,
+    },
+    NormalMap {
+        file: Option<String>,
+    },
+    BumpMap {
+        file: Option<String>,
+        strength: f64,
+    },
+    // source is perlin, turbulence or the file of a height map, or none to turn it off
+    Displace {
+        source: Option<String>,
+        amount: f64,
+        levels: usize,
+        scale: f64,
+    },
+    // density grid filling the box between two corners, with densities multiplied by `scale`
+    Volume {
+        file: String,
+        a: [f64; 3],
+        b: [f64; 3],
+        scale: f64,
+    },
+    // a density of zero or less turns fog off
+    Fog {
+        density: f64,
+        color: [f64; 3],
+    },
+    // fills the objects which follow, `medium none` parses as a density of zero
+    Medium {
+        density: f64,
+        color: [f64; 3],
+    },
+    Pattern {
+        kind: PatternKind,
+        scale: f64,
+        a: [f64; 3],
+        b: [f64; 3],
+        space: PatternSpace,
+    },
 }
 
 impl FromStr for FileEntry {
@@ -94,6 +351,75 @@ impl FromStr for FileEntry {
                 };
                 Ok(FileEntry::Sphere { x, y, z, r })
             }
+            "cylinder" | "cone" | "disk" | "box" => {
+                let count = match parts[0] {
+                    "box" => 6,
+                    _ => 7,
+                };
+                let values = match parts[1..count + 1]
+                    .iter()
+                    .map(|p| p.parse::<f64>())
+                    .collect::<Result<Vec<f64>, _>>()
+                {
+                    Ok(v) => v,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let a = [values[0], values[1], values[2]];
+                let b = [values[3], values[4], values[5]];
+                // only cylinders and cones have caps to leave off
+                let capped = match parts.get(count + 1) {
+                    Some(&"capped") if matches!(parts[0], "cylinder" | "cone") => true,
+                    Some(other) => return Err(format!("Unknown option: {}", other)),
+                    None => false,
+                };
+                match parts[0] {
+                    "cylinder" => Ok(FileEntry::Cylinder {
+                        a,
+                        b,
+                        r: values[6],
+                        capped,
+                    }),
+                    "cone" => Ok(FileEntry::Cone {
+                        a,
+                        b,
+                        r: values[6],
+                        capped,
+                    }),
+                    "disk" => Ok(FileEntry::Disk {
+                        xyz: a,
+                        n: b,
+                        r: values[6],
+                    }),
+                    _ => Ok(FileEntry::Box { a, b }),
+                }
+            }
+            "torus" | "quadric" => {
+                let count = match parts[0] {
+                    "torus" => 8,
+                    _ => 10,
+                };
+                let values = match parts[1..count + 1]
+                    .iter()
+                    .map(|p| p.parse::<f64>())
+                    .collect::<Result<Vec<f64>, _>>()
+                {
+                    Ok(v) => v,
+                    Err(e) => return Err(e.to_string()),
+                };
+                match parts[0] {
+                    "torus" => Ok(FileEntry::Torus {
+                        xyz: [values[0], values[1], values[2]],
+                        axis: [values[3], values[4], values[5]],
+                        major: values[6],
+                        minor: values[7],
+                    }),
+                    _ => {
+                        let mut coefficients = [0.0; 10];
+                        coefficients.copy_from_slice(&values);
+                        Ok(FileEntry::Quadric { coefficients })
+                    }
+                }
+            }
             "sun" => {
                 let x = match parts[1].parse::<f64>() {
                     Ok(x) => x,
@@ -173,6 +499,33 @@ impl FromStr for FileEntry {
                 };
                 Ok(FileEntry::Triangle { a, b, c })
             }
+            "quadf" => {
+                let indices = match parts[1..5]
+                    .iter()
+                    .map(|p| p.parse::<i32>())
+                    .collect::<Result<Vec<i32>, _>>()
+                {
+                    Ok(indices) => indices,
+                    Err(e) => return Err(e.to_string()),
+                };
+                Ok(FileEntry::Quad {
+                    a: indices[0],
+                    b: indices[1],
+                    c: indices[2],
+                    d: indices[3],
+                })
+            }
+            "crease" => {
+                let a = match parts[1].parse::<i32>() {
+                    Ok(a) => a,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let b = match parts[2].parse::<i32>() {
+                    Ok(b) => b,
+                    Err(e) => return Err(e.to_string()),
+                };
+                Ok(FileEntry::Crease { a, b })
+            }
             "bulb" => {
                 let x = match parts[1].parse::<f64>() {
                     Ok(x) => x,
@@ -188,6 +541,25 @@ impl FromStr for FileEntry {
                 };
                 Ok(FileEntry::Bulb { x, y, z })
             }
+            "intensity" => match parts[1].parse::<f64>() {
+                Ok(i) => Ok(FileEntry::Intensity { i }),
+                Err(e) => Err(e.to_string()),
+            },
+            "falloff" => {
+                let falloff = match parts[1] {
+                    "none" => Falloff::None,
+                    "linear" => Falloff::Linear,
+                    "quadratic" => Falloff::Quadratic,
+                    "range" => match parts.get(2).map(|r| r.parse::<f64>()) {
+                        Some(Ok(r)) if r > 0.0 => Falloff::Range(r),
+                        Some(Ok(_)) => return Err("Falloff range must be positive".to_string()),
+                        Some(Err(e)) => return Err(e.to_string()),
+                        None => return Err("Falloff range is missing its distance".to_string()),
+                    },
+                    f => return Err(format!("Unknown falloff: {}", f)),
+                };
+                Ok(FileEntry::Falloff { falloff })
+            }
             "eye" => {
                 let x = match parts[1].parse::<f64>() {
                     Ok(x) => x,
@@ -247,6 +619,13 @@ impl FromStr for FileEntry {
                 };
                 Ok(FileEntry::Shiny { s })
             }
+            "transparency" => match parts[1].parse::<f64>() {
+                Ok(t) if (0.0..=1.0).contains(&t) => Ok(FileEntry::Transparency { t }),
+                Ok(_) => Err("Transparency must be between 0 and 1".to_string()),
+                Err(e) => Err(e.to_string()),
+            },
+            "onesided" => Ok(FileEntry::OneSided),
+            "twosided" => Ok(FileEntry::TwoSided),
             "bounces" => match parts[1].parse::<usize>() {
                 Ok(b) => Ok(FileEntry::Bounces { b }),
                 Err(e) => Err(e.to_string()),
@@ -255,6 +634,404 @@ impl FromStr for FileEntry {
                 Ok(n) => Ok(FileEntry::Aa { n }),
                 Err(e) => Err(e.to_string()),
             },
+            "ao" => {
+                let samples = match parts[1].parse::<usize>() {
+                    Ok(s) => s,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let distance = match parts[2].parse::<f64>() {
+                    Ok(d) => d,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let only = match parts.get(3) {
+                    Some(&"only") => true,
+                    Some(s) => return Err(format!("Unknown ao option: {}", s)),
+                    None => false,
+                };
+                Ok(FileEntry::Ao {
+                    samples,
+                    distance,
+                    only,
+                })
+            }
+            "photons" => {
+                let count = match parts[1].parse::<usize>() {
+                    Ok(c) => c,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let radius = match parts.get(2) {
+                    Some(r) => match r.parse::<f64>() {
+                        Ok(r) if r > 0.0 => r,
+                        Ok(_) => return Err("Photon radius must be positive".to_string()),
+                        Err(e) => return Err(e.to_string()),
+                    },
+                    None => 0.1,
+                };
+                Ok(FileEntry::Photons { count, radius })
+            }
+            "lightsamples" => match parts[1].parse::<usize>() {
+                Ok(n) => Ok(FileEntry::LightSamples { n }),
+                Err(e) => Err(e.to_string()),
+            },
+            "smooth" => match parts[1].parse::<f64>() {
+                Ok(angle) => Ok(FileEntry::Smooth { angle }),
+                Err(e) => Err(e.to_string()),
+            },
+            "weld" => match parts[1].parse::<f64>() {
+                Ok(tolerance) => Ok(FileEntry::Weld { tolerance }),
+                Err(e) => Err(e.to_string()),
+            },
+            "subdivide" => match parts[1].parse::<usize>() {
+                Ok(levels) if levels <= MAX_SUBDIVISION_LEVELS => {
+                    Ok(FileEntry::Subdivide { levels })
+                }
+                Ok(_) => Err(format!(
+                    "Meshes can be subdivided at most {} times",
+                    MAX_SUBDIVISION_LEVELS
+                )),
+                Err(e) => Err(e.to_string()),
+            },
+            "texture" => {
+                let file = match parts[1] {
+                    "none" => None,
+                    f => Some(f.to_string()),
+                };
+                let wrap = match parts.get(2) {
+                    Some(w) => WrapMode::from_str(w)?,
+                    None => WrapMode::Repeat,
+                };
+                Ok(FileEntry::Texture { file, wrap })
+            }
+            "texcoord" => {
+                let u = match parts[1].parse::<f64>() {
+                    Ok(u) => u,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let v = match parts[2].parse::<f64>() {
+                    Ok(v) => v,
+                    Err(e) => return Err(e.to_string()),
+                };
+                Ok(FileEntry::Texcoord { u, v })
+            }
+            "translate" => {
+                let x = match parts[1].parse::<f64>() {
+                    Ok(x) => x,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let y = match parts[2].parse::<f64>() {
+                    Ok(y) => y,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let z = match parts[3].parse::<f64>() {
+                    Ok(z) => z,
+                    Err(e) => return Err(e.to_string()),
+                };
+                Ok(FileEntry::Translate { x, y, z })
+            }
+            "rotate" => {
+                let x = match parts[1].parse::<f64>() {
+                    Ok(x) => x,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let y = match parts[2].parse::<f64>() {
+                    Ok(y) => y,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let z = match parts[3].parse::<f64>() {
+                    Ok(z) => z,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let degrees = match parts[4].parse::<f64>() {
+                    Ok(d) => d,
+                    Err(e) => return Err(e.to_string()),
+                };
+                Ok(FileEntry::Rotate { x, y, z, degrees })
+            }
+            "scale" => {
+                let x = match parts[1].parse::<f64>() {
+                    Ok(x) => x,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let y = match parts[2].parse::<f64>() {
+                    Ok(y) => y,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let z = match parts[3].parse::<f64>() {
+                    Ok(z) => z,
+                    Err(e) => return Err(e.to_string()),
+                };
+                Ok(FileEntry::Scale { x, y, z })
+            }
+            "push" => Ok(FileEntry::Push),
+            "pop" => Ok(FileEntry::Pop),
+            "define" => Ok(FileEntry::Define {
+                name: parts[1].to_string(),
+            }),
+            "end" => Ok(FileEntry::End),
+            "terrain" => {
+                let size = match parts[1].parse::<f64>() {
+                    Ok(s) => s,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let resolution = match parts[2].parse::<usize>() {
+                    Ok(r) if r >= 2 => r,
+                    Ok(_) => return Err("Terrain needs a resolution of at least 2".to_string()),
+                    Err(e) => return Err(e.to_string()),
+                };
+                let iterations = match parts[3].parse::<usize>() {
+                    Ok(i) => i,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let seed = match parts[4].parse::<u64>() {
+                    Ok(s) => s,
+                    Err(e) => return Err(e.to_string()),
+                };
+                Ok(FileEntry::Terrain {
+                    size,
+                    resolution,
+                    iterations,
+                    seed,
+                })
+            }
+            "sphereflake" | "menger" => {
+                let depth = match parts[1].parse::<usize>() {
+                    Ok(d) if d <= MAX_FRACTAL_DEPTH => d,
+                    Ok(_) => {
+                        return Err(format!(
+                            "Fractal depth can be at most {}",
+                            MAX_FRACTAL_DEPTH
+                        ))
+                    }
+                    Err(e) => return Err(e.to_string()),
+                };
+                match parts[0] {
+                    "sphereflake" => Ok(FileEntry::Sphereflake { depth }),
+                    _ => Ok(FileEntry::Menger { depth }),
+                }
+            }
+            "blob" => match parts[1].parse::<f64>() {
+                Ok(threshold) => Ok(FileEntry::Blob { threshold }),
+                Err(e) => Err(e.to_string()),
+            },
+            "ball" => {
+                let values = match parts[1..5]
+                    .iter()
+                    .map(|p| p.parse::<f64>())
+                    .collect::<Result<Vec<f64>, _>>()
+                {
+                    Ok(v) => v,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let weight = match parts.get(5).map(|p| p.parse::<f64>()) {
+                    Some(Ok(w)) => w,
+                    Some(Err(e)) => return Err(e.to_string()),
+                    None => 1.0,
+                };
+                Ok(FileEntry::Ball {
+                    xyz: [values[0], values[1], values[2]],
+                    r: values[3],
+                    weight,
+                })
+            }
+            "sdfbox" | "sdfcapsule" | "sdftorus" | "sdfblend" => {
+                let count = match parts[0] {
+                    "sdfbox" | "sdfcapsule" => 7,
+                    "sdftorus" => 5,
+                    _ => 1,
+                };
+                let values = match parts[1..count + 1]
+                    .iter()
+                    .map(|p| p.parse::<f64>())
+                    .collect::<Result<Vec<f64>, _>>()
+                {
+                    Ok(v) => v,
+                    Err(e) => return Err(e.to_string()),
+                };
+                match parts[0] {
+                    "sdfbox" => Ok(FileEntry::SdfBox {
+                        xyz: [values[0], values[1], values[2]],
+                        half: [values[3], values[4], values[5]],
+                        radius: values[6],
+                    }),
+                    "sdfcapsule" => Ok(FileEntry::SdfCapsule {
+                        a: [values[0], values[1], values[2]],
+                        b: [values[3], values[4], values[5]],
+                        r: values[6],
+                    }),
+                    "sdftorus" => Ok(FileEntry::SdfTorus {
+                        xyz: [values[0], values[1], values[2]],
+                        major: values[3],
+                        minor: values[4],
+                    }),
+                    _ => Ok(FileEntry::SdfBlend { k: values[0] }),
+                }
+            }
+            "sdfrepeat" => {
+                let spacing = match parts[1..4]
+                    .iter()
+                    .map(|p| p.parse::<f64>())
+                    .collect::<Result<Vec<f64>, _>>()
+                {
+                    Ok(v) => [v[0], v[1], v[2]],
+                    Err(e) => return Err(e.to_string()),
+                };
+                let count = match parts[4..7]
+                    .iter()
+                    .map(|p| p.parse::<usize>())
+                    .collect::<Result<Vec<usize>, _>>()
+                {
+                    Ok(v) => [v[0], v[1], v[2]],
+                    Err(e) => return Err(e.to_string()),
+                };
+                if count.contains(&0) {
+                    return Err("sdfrepeat needs at least one copy along each axis".to_string());
+                }
+                Ok(FileEntry::SdfRepeat { spacing, count })
+            }
+            "csg" => Ok(FileEntry::Csg {
+                operation: CsgOperation::from_str(parts[1])?,
+            }),
+            "instance" => {
+                let material = match parts.get(2) {
+                    Some(&"material") => true,
+                    Some(other) => return Err(format!("Unknown instance option: {}", other)),
+                    None => false,
+                };
+                Ok(FileEntry::Instance {
+                    name: parts[1].to_string(),
+                    material,
+                })
+            }
+            "normalmap" => {
+                let file = match parts[1] {
+                    "none" => None,
+                    f => Some(f.to_string()),
+                };
+                Ok(FileEntry::NormalMap { file })
+            }
+            "bumpmap" => {
+                let file = match parts[1] {
+                    "none" => None,
+                    f => Some(f.to_string()),
+                };
+                let strength = match parts.get(2) {
+                    Some(s) => match s.parse::<f64>() {
+                        Ok(s) => s,
+                        Err(e) => return Err(e.to_string()),
+                    },
+                    None => 1.0,
+                };
+                Ok(FileEntry::BumpMap { file, strength })
+            }
+            "displace" => {
+                let source = match parts[1] {
+                    "none" => {
+                        return Ok(FileEntry::Displace {
+                            source: None,
+                            amount: 0.0,
+                            levels: 0,
+                            scale: 1.0,
+                        })
+                    }
+                    s => s.to_string(),
+                };
+                let amount = match parts[2].parse::<f64>() {
+                    Ok(a) => a,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let levels = match parts[3].parse::<usize>() {
+                    Ok(l) if l <= MAX_DISPLACEMENT_LEVELS => l,
+                    Ok(_) => {
+                        return Err(format!(
+                            "Displacement can subdivide at most {} times",
+                            MAX_DISPLACEMENT_LEVELS
+                        ))
+                    }
+                    Err(e) => return Err(e.to_string()),
+                };
+                let scale = match parts.get(4) {
+                    Some(s) => match s.parse::<f64>() {
+                        Ok(s) => s,
+                        Err(e) => return Err(e.to_string()),
+                    },
+                    None => 1.0,
+                };
+                Ok(FileEntry::Displace {
+                    source: Some(source),
+                    amount,
+                    levels,
+                    scale,
+                })
+            }
+            "volume" => {
+                let values = match parts[2..8]
+                    .iter()
+                    .map(|p| p.parse::<f64>())
+                    .collect::<Result<Vec<f64>, _>>()
+                {
+                    Ok(values) => values,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let scale = match parts.get(8) {
+                    Some(s) => match s.parse::<f64>() {
+                        Ok(s) => s,
+                        Err(e) => return Err(e.to_string()),
+                    },
+                    None => 1.0,
+                };
+                Ok(FileEntry::Volume {
+                    file: parts[1].to_string(),
+                    a: [values[0], values[1], values[2]],
+                    b: [values[3], values[4], values[5]],
+                    scale,
+                })
+            }
+            "medium" if parts[1] == "none" => Ok(FileEntry::Medium {
+                density: 0.0,
+                color: [0.0; 3],
+            }),
+            "fog" | "medium" => {
+                let values = match parts[1..5]
+                    .iter()
+                    .map(|p| p.parse::<f64>())
+                    .collect::<Result<Vec<f64>, _>>()
+                {
+                    Ok(values) => values,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let density = values[0];
+                let color = [values[1], values[2], values[3]];
+                match parts[0] {
+                    "fog" => Ok(FileEntry::Fog { density, color }),
+                    _ => Ok(FileEntry::Medium { density, color }),
+                }
+            }
+            "checker" | "perlin" | "turbulence" | "marble" | "wood" => {
+                let kind = PatternKind::from_str(parts[0])?;
+                let values = match parts[1..8]
+                    .iter()
+                    .map(|p| p.parse::<f64>())
+                    .collect::<Result<Vec<f64>, _>>()
+                {
+                    Ok(v) => v,
+                    Err(e) => return Err(e.to_string()),
+                };
+                // positions are divided by the scale
+                if values[0] <= 0.0 {
+                    return Err("Pattern scale must be positive".to_string());
+                }
+                let space = match parts.get(8) {
+                    Some(s) => PatternSpace::from_str(s)?,
+                    None => PatternSpace::World,
+                };
+                Ok(FileEntry::Pattern {
+                    kind,
+                    scale: values[0],
+                    a: [values[1], values[2], values[3]],
+                    b: [values[4], values[5], values[6]],
+                    space,
+                })
+            }
             _ => Err(format!("Unknown file entry: {}", s)),
         }
     }
@@ -296,3 +1073,45 @@ pub fn parse_file(path: PathBuf) -> Result<ProcFile, String> {
     }
     Ok(ProcFile { header, entries })
 }
+
+#[cfg(test)]
+mod parser_tests {
+    use super::*;
+
+    #[test]
+    fn only_cylinders_and_cones_can_be_capped() {
+        assert!(FileEntry::from_str("cylinder 0 0 0 0 1 0 1 capped").is_ok());
+        assert!(FileEntry::from_str("cone 0 0 0 0 1 0 1 capped").is_ok());
+        assert!(FileEntry::from_str("disk 0 0 0 0 1 0 1 capped").is_err());
+        assert!(FileEntry::from_str("box 0 0 0 1 1 1 capped").is_err());
+    }
+
+    #[test]
+    fn pattern_scales_must_be_positive() {
+        assert!(FileEntry::from_str("checker 0.5 0 0 0 1 1 1").is_ok());
+        assert!(FileEntry::from_str("checker 0 0 0 0 1 1 1").is_err());
+        assert!(FileEntry::from_str("wood -1 0 0 0 1 1 1 object").is_err());
+    }
+
+    #[test]
+    fn instances_only_take_a_material_override() {
+        assert!(FileEntry::from_str("instance pair").is_ok());
+        assert!(FileEntry::from_str("instance pair material").is_ok());
+        assert!(FileEntry::from_str("instance pair color").is_err());
+    }
+
+    #[test]
+    fn fractal_depths_are_limited() {
+        assert!(FileEntry::from_str("sphereflake 4").is_ok());
+        assert!(FileEntry::from_str("menger 4").is_ok());
+        assert!(FileEntry::from_str("sphereflake 5").is_err());
+        assert!(FileEntry::from_str("menger 41").is_err());
+    }
+
+    #[test]
+    fn subdivision_levels_are_limited() {
+        assert!(FileEntry::from_str("subdivide 0").is_ok());
+        assert!(FileEntry::from_str("subdivide 6").is_ok());
+        assert!(FileEntry::from_str("subdivide 7").is_err());
+    }
+}
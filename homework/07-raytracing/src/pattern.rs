@@ -0,0 +1,213 @@
+use crate::utils::Rng;
+use nalgebra::{Point3, Vector3};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+const TURBULENCE_OCTAVES: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PatternKind {
+    Checker,
+    Perlin,
+    Turbulence,
+    Marble,
+    Wood,
+}
+
+impl FromStr for PatternKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "checker" => Ok(PatternKind::Checker),
+            "perlin" => Ok(PatternKind::Perlin),
+            "turbulence" => Ok(PatternKind::Turbulence),
+            "marble" => Ok(PatternKind::Marble),
+            "wood" => Ok(PatternKind::Wood),
+            _ => Err(format!("Unknown pattern: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PatternSpace {
+    World,
+    Object,
+}
+
+impl FromStr for PatternSpace {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "world" => Ok(PatternSpace::World),
+            "object" => Ok(PatternSpace::Object),
+            _ => Err(format!("Unknown pattern space: {}", s)),
+        }
+    }
+}
+
+// A color which varies over space, blending between two colors
+#[derive(Debug, Clone, Copy)]
+pub struct Pattern {
+    pub kind: PatternKind,
+    pub scale: f64,
+    pub a: Vector3<f64>,
+    pub b: Vector3<f64>,
+    pub space: PatternSpace,
+}
+
+// doubled permutation table for the noise function, shuffled once with a fixed seed so that
+// renders are repeatable
+fn permutation() -> &'static [usize; 512] {
+    static PERMUTATION: OnceLock<[usize; 512]> = OnceLock::new();
+    PERMUTATION.get_or_init(|| {
+        let rng = Rng::new(418);
+        let mut p: Vec<usize> = (0..256).collect();
+        for i in (1..256).rev() {
+            let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+            p.swap(i, j);
+        }
+        let mut table = [0; 512];
+        for i in 0..512 {
+            table[i] = p[i % 256];
+        }
+        table
+    })
+}
+
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    a + t * (b - a)
+}
+
+fn grad(hash: usize, x: f64, y: f64, z: f64) -> f64 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = match h {
+        h if h < 4 => y,
+        12 | 14 => x,
+        _ => z,
+    };
+    let u = if h & 1 == 0 { u } else { -u };
+    let v = if h & 2 == 0 { v } else { -v };
+    u + v
+}
+
+// Ken Perlin's improved gradient noise, roughly in [-1, 1]
+pub fn noise(p: &Point3<f64>) -> f64 {
+    let perm = permutation();
+    let cell = p.map(|c| c.floor());
+    let [x, y, z] = [p.x - cell.x, p.y - cell.y, p.z - cell.z];
+    let [xi, yi, zi] = [cell.x, cell.y, cell.z].map(|c| (c as i64).rem_euclid(256) as usize);
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+
+    let a = perm[xi] + yi;
+    let aa = perm[a] + zi;
+    let ab = perm[a + 1] + zi;
+    let b = perm[xi + 1] + yi;
+    let ba = perm[b] + zi;
+    let bb = perm[b + 1] + zi;
+
+    lerp(
+        w,
+        lerp(
+            v,
+            lerp(u, grad(perm[aa], x, y, z), grad(perm[ba], x - 1.0, y, z)),
+            lerp(
+                u,
+                grad(perm[ab], x, y - 1.0, z),
+                grad(perm[bb], x - 1.0, y - 1.0, z),
+            ),
+        ),
+        lerp(
+            v,
+            lerp(
+                u,
+                grad(perm[aa + 1], x, y, z - 1.0),
+                grad(perm[ba + 1], x - 1.0, y, z - 1.0),
+            ),
+            lerp(
+                u,
+                grad(perm[ab + 1], x, y - 1.0, z - 1.0),
+                grad(perm[bb + 1], x - 1.0, y - 1.0, z - 1.0),
+            ),
+        ),
+    )
+}
+
+// sum of the absolute value of several octaves of noise, roughly in [0, 1]
+pub fn turbulence(p: &Point3<f64>) -> f64 {
+    let mut sum = 0.0;
+    let mut frequency = 1.0;
+    for _ in 0..TURBULENCE_OCTAVES {
+        sum += noise(&p.map(|c| c * frequency)).abs() / frequency;
+        frequency *= 2.0;
+    }
+    sum
+}
+
+impl Pattern {
+    // weight of `b` against `a` at a point already scaled into pattern space
+    fn blend(&self, p: &Point3<f64>) -> f64 {
+        match self.kind {
+            PatternKind::Checker => {
+                let sum = p.x.floor() + p.y.floor() + p.z.floor();
+                match sum.rem_euclid(2.0) {
+                    s if s < 1.0 => 0.0,
+                    _ => 1.0,
+                }
+            }
+            PatternKind::Perlin => 0.5 * (noise(p) + 1.0),
+            PatternKind::Turbulence => turbulence(p),
+            PatternKind::Marble => 0.5 * (1.0 + (p.x + 5.0 * turbulence(p)).sin()),
+            PatternKind::Wood => {
+                let rings = (p.x * p.x + p.z * p.z).sqrt() + 0.5 * turbulence(p);
+                rings.fract()
+            }
+        }
+    }
+
+    // color of the pattern at a point in world or object space, whichever `space` asks for
+    pub fn color(&self, p: &Point3<f64>) -> Vector3<f64> {
+        let t = self.blend(&p.map(|c| c / self.scale)).clamp(0.0, 1.0);
+        self.a.scale(1.0 - t) + self.b.scale(t)
+    }
+}
+
+#[cfg(test)]
+mod pattern_tests {
+    use super::*;
+
+    fn pattern(kind: PatternKind) -> Pattern {
+        Pattern {
+            kind,
+            scale: 2.0,
+            a: Vector3::zeros(),
+            b: Vector3::new(1.0, 1.0, 1.0),
+            space: PatternSpace::World,
+        }
+    }
+
+    #[test]
+    fn checker_alternates_every_scale_units() {
+        let p = pattern(PatternKind::Checker);
+        assert_eq!(0.0, p.color(&Point3::new(0.5, 0.5, 0.5)).x);
+        assert_eq!(1.0, p.color(&Point3::new(2.5, 0.5, 0.5)).x);
+        assert_eq!(1.0, p.color(&Point3::new(-0.5, 0.5, 0.5)).x);
+        assert_eq!(0.0, p.color(&Point3::new(2.5, 2.5, 0.5)).x);
+    }
+
+    #[test]
+    fn noise_is_zero_on_lattice_points() {
+        assert_eq!(0.0, noise(&Point3::new(3.0, -7.0, 12.0)));
+    }
+
+    #[test]
+    fn noise_is_continuous() {
+        let a = noise(&Point3::new(1.5, 2.25, 3.75));
+        let b = noise(&Point3::new(1.5 + 1e-7, 2.25, 3.75));
+        assert!((a - b).abs() < 1e-5);
+    }
+}
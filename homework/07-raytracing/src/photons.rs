@@ -0,0 +1,453 @@
+use crate::models::{Falloff, LightPrimitive, ObjPrimative, SceneObject, AABB};
+use crate::raytracer::{Ray, RayTracer};
+use crate::scene::Scene;
+use crate::utils::{orthonormal_basis, Rng};
+use nalgebra::{Point3, Vector3};
+use std::f64::consts::PI;
+use std::ops::Sub;
+
+// A packet of light which reached a diffuse surface by way of at least one mirror
+#[derive(Debug, Clone, Copy)]
+pub struct Photon {
+    pub position: Point3<f64>,
+    // direction the photon was travelling in when it landed
+    pub direction: Vector3<f64>,
+    pub power: Vector3<f64>,
+}
+
+// Photons ordered as a balanced kd-tree. The photon splitting each range lies at its middle,
+// with the photons below it along `axes` to its left and those above it to its right.
+#[derive(Debug)]
+pub struct KdTree {
+    photons: Vec<Photon>,
+    axes: Vec<usize>,
+}
+
+impl KdTree {
+    pub fn new(mut photons: Vec<Photon>) -> Self {
+        let mut axes = vec![0; photons.len()];
+        Self::build(&mut photons, &mut axes);
+        Self { photons, axes }
+    }
+
+    fn build(photons: &mut [Photon], axes: &mut [usize]) {
+        if photons.is_empty() {
+            return;
+        }
+        // split along the axis in which the photons are most spread out
+        let (min, max) = photons.iter().fold(
+            (photons[0].position, photons[0].position),
+            |(min, max), p| (min.inf(&p.position), max.sup(&p.position)),
+        );
+        let axis = max.sub(min).imax();
+        let middle = photons.len() / 2;
+        photons
+            .select_nth_unstable_by(middle, |a, b| a.position[axis].total_cmp(&b.position[axis]));
+        axes[middle] = axis;
+        let (left, right) = photons.split_at_mut(middle);
+        let (left_axes, right_axes) = axes.split_at_mut(middle);
+        Self::build(left, left_axes);
+        Self::build(&mut right[1..], &mut right_axes[1..]);
+    }
+
+    // every photon within `radius` of `p`
+    pub fn within(&self, p: &Point3<f64>, radius: f64) -> Vec<&Photon> {
+        let mut found = vec![];
+        self.search(0, self.photons.len(), p, radius, &mut found);
+        found
+    }
+
+    fn search<'a>(
+        &'a self,
+        start: usize,
+        end: usize,
+        p: &Point3<f64>,
+        radius: f64,
+        found: &mut Vec<&'a Photon>,
+    ) {
+        if start >= end {
+            return;
+        }
+        let middle = start + (end - start) / 2;
+        let photon = &self.photons[middle];
+        if photon.position.sub(p).magnitude_squared() <= radius * radius {
+            found.push(photon);
+        }
+        let offset = p[self.axes[middle]] - photon.position[self.axes[middle]];
+        let (near, far) = match offset < 0.0 {
+            true => ((start, middle), (middle + 1, end)),
+            false => ((middle + 1, end), (start, middle)),
+        };
+        self.search(near.0, near.1, p, radius, found);
+        if offset * offset <= radius * radius {
+            self.search(far.0, far.1, p, radius, found);
+        }
+    }
+}
+
+// Caustic photons, which are gathered at diffuse surfaces to add light focused onto them by
+// mirrors. The renderer does not bend light through transparent surfaces, so photons pass
+// straight through them, filtered as shadow rays are, and only mirrors focus light.
+#[derive(Debug)]
+pub struct PhotonMap {
+    tree: KdTree,
+    radius: f64,
+}
+
+impl PhotonMap {
+    pub fn new(photons: Vec<Photon>, radius: f64) -> Self {
+        Self {
+            tree: KdTree::new(photons),
+            radius,
+        }
+    }
+
+    // light arriving at a surface from the photons landing near it, which is their power over
+    // the area they are gathered from
+    pub fn gather(&self, p: &Point3<f64>, n: &Vector3<f64>) -> Vector3<f64> {
+        let power: Vector3<f64> = self
+            .tree
+            .within(p, self.radius)
+            .iter()
+            .filter(|photon| photon.direction.dot(n) < 0.0)
+            .map(|photon| photon.power)
+            .sum();
+        power.scale(1.0 / (PI * self.radius * self.radius))
+    }
+}
+
+// whether an object may reflect photons, which for an instance is whether any of the objects
+// it draws may
+fn is_mirror(scene: &Scene, object: &SceneObject) -> bool {
+    match object.primitive {
+        ObjPrimative::Instance {
+            definition,
+            override_material: false,
+            ..
+        } => scene.definitions[definition]
+            .objects
+            .iter()
+            .any(|o| is_mirror(scene, o)),
+        _ => object.material.shininess > 0.0,
+    }
+}
+
+// bounding sphere of a box
+fn bounding_sphere(aabb: &AABB) -> (Point3<f64>, f64) {
+    (
+        aabb.min + aabb.max.sub(aabb.min).scale(0.5),
+        aabb.max.sub(aabb.min).magnitude() / 2.0,
+    )
+}
+
+// Spheres around the mirrors in the scene, which photons are aimed at directly as most of space
+// is empty. Unbounded mirrors are aimed at where they lie under the bounded objects of the
+// scene, which for a plane is the sphere around them moved onto it, and which a scene without
+// bounded objects does not have.
+fn mirror_targets(scene: &Scene) -> Vec<(Point3<f64>, f64)> {
+    let bounds = scene.bvh.bounding_volume.aabb;
+    let scene_sphere = match bounds.min.x <= bounds.max.x {
+        true => Some(bounding_sphere(&bounds)),
+        false => None,
+    };
+    scene
+        .objects
+        .iter()
+        .filter(|o| is_mirror(scene, o))
+        .filter_map(|o| match (o.aabb, o.primitive) {
+            (Some(aabb), _) => Some(bounding_sphere(&aabb)),
+            (None, ObjPrimative::Plane { n, p }) => {
+                let (n, p) = match o.transform {
+                    Some(t) => (t.normal(&n), t.point(&p)),
+                    None => (n.normalize(), p),
+                };
+                scene_sphere.map(|(c, r)| (c - n.scale(n.dot(&c.sub(p))), r))
+            }
+            (None, _) => scene_sphere,
+        })
+        .collect()
+}
+
+// whether a photon sent along `ray` from `light` could also have been aimed at the sphere around
+// `center`, which is when it lies in the cone or the disk used to aim at it
+fn aimed_at(light: &LightPrimitive, ray: &Ray, center: &Point3<f64>, radius: f64) -> bool {
+    let d = ray.direction.normalize();
+    let to_center = center.sub(ray.origin);
+    match light {
+        LightPrimitive::Directional(_) => {
+            (to_center - d.scale(to_center.dot(&d))).magnitude() <= radius
+        }
+        LightPrimitive::Point(_) => {
+            let distance = to_center.magnitude();
+            distance <= radius
+                || d.dot(&to_center) / distance >= (1.0 - (radius / distance).powi(2)).sqrt()
+        }
+    }
+}
+
+// uniformly random direction in the cone around `axis` with a half angle whose cosine is
+// `cos_max`
+fn cone_direction(axis: &Vector3<f64>, cos_max: f64, rng: &Rng) -> Vector3<f64> {
+    let cos = 1.0 - rng.next_f64() * (1.0 - cos_max);
+    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+    let phi = 2.0 * PI * rng.next_f64();
+    let (u, v) = orthonormal_basis(axis);
+    u.scale(sin * phi.cos()) + v.scale(sin * phi.sin()) + axis.scale(cos)
+}
+
+// Sends `count` photons from every light, split between the mirrors in the scene, and keeps
+// those which land on a diffuse surface after bouncing off of one. Each photon carries the
+// light's power over the solid angle or area it was sent through, shared between the photons.
+// Where the cones or disks aimed at different mirrors overlap, photons are sent through the
+// overlap once for each of them, so their power is split between those mirrors.
+pub fn trace_photons(
+    scene: &Scene,
+    ray_tracer: &RayTracer,
+    count: usize,
+    max_bounces: usize,
+    rng: &Rng,
+) -> Vec<Photon> {
+    let spheres = mirror_targets(scene);
+    let mut photons = vec![];
+    if spheres.is_empty() {
+        return photons;
+    }
+    let per_mirror = (count / spheres.len()).max(1);
+    let scene_bounds = scene.bvh.bounding_volume.aabb;
+    for light in &scene.light_sources {
+        for (m, &(center, radius)) in spheres.iter().enumerate() {
+            for _ in 0..per_mirror {
+                let falloff = match light.source {
+                    LightPrimitive::Directional(_) => None,
+                    LightPrimitive::Point(_) => Some(light.falloff),
+                };
+                let (ray, power) = match light.source {
+                    // from a disk in front of the mirror, far enough back to be outside the scene
+                    LightPrimitive::Directional(d) => {
+                        let back = scene_bounds.max.sub(scene_bounds.min).magnitude()
+                            + center.sub(scene_bounds.min).magnitude()
+                            + radius;
+                        let (u, v) = orthonormal_basis(&d);
+                        let r = radius * rng.next_f64().sqrt();
+                        let phi = 2.0 * PI * rng.next_f64();
+                        let offset = u.scale(r * phi.cos()) + v.scale(r * phi.sin());
+                        let area = PI * radius * radius;
+                        let ray = Ray::new(center + offset + d.scale(back), -d);
+                        (
+                            ray,
+                            light
+                                .brightness(f64::INFINITY)
+                                .scale(area / per_mirror as f64),
+                        )
+                    }
+                    // through the cone from the bulb around the mirror
+                    LightPrimitive::Point(p) => {
+                        let to_center = center.sub(p);
+                        let distance = to_center.magnitude();
+                        let cos_max = match distance > radius {
+                            true => (1.0 - (radius / distance).powi(2)).sqrt(),
+                            false => -1.0,
+                        };
+                        let axis = to_center.normalize();
+                        let solid_angle = 2.0 * PI * (1.0 - cos_max);
+                        let ray = Ray::new(p, cone_direction(&axis, cos_max, rng));
+                        let power = light.color.scale(light.intensity * solid_angle);
+                        (ray, power.scale(1.0 / per_mirror as f64))
+                    }
+                };
+                let overlapping = spheres
+                    .iter()
+                    .enumerate()
+                    .filter(|&(other, (c, r))| other != m && aimed_at(&light.source, &ray, c, *r))
+                    .count();
+                photons.extend(trace_photon(
+                    scene,
+                    ray_tracer,
+                    ray,
+                    power.scale(1.0 / (overlapping + 1) as f64),
+                    falloff,
+                    max_bounces,
+                    rng,
+                ));
+            }
+        }
+    }
+    photons
+}
+
+// photons left on diffuse surfaces along the path of one sent from a light
+fn trace_photon(
+    scene: &Scene,
+    ray_tracer: &RayTracer,
+    mut ray: Ray,
+    mut power: Vector3<f64>,
+    falloff: Option<Falloff>,
+    max_bounces: usize,
+    rng: &Rng,
+) -> Vec<Photon> {
+    let mut photons = vec![];
+    let mut bounces = 0;
+    let mut travelled = 0.0;
+    while let Some(hit) = ray_tracer.trace_ray(&ray) {
+        travelled += hit.distance;
+        let object = &scene.hit_object(&hit).unwrap();
+        // media and volumes are passed straight through
+        if scene.interior(object).is_some() {
+            ray = hit.spawn_ray(ray.direction);
+            continue;
+        }
+        let shininess = object.material.shininess;
+        if bounces > 0 && shininess < 1.0 {
+            photons.push(Photon {
+                position: hit.position,
+                direction: ray.direction,
+                // photons spread out as the square of the distance, so other falloffs make up
+                // the difference
+                power: match falloff {
+                    Some(f) => power.scale(f.attenuation(travelled) * travelled * travelled),
+                    None => power,
+                },
+            });
+        }
+        // transparent surfaces let through as much light as they are transparent, and mirrors
+        // reflect as much of the rest as they are shiny, so photons go on with those chances
+        if rng.next_f64() < object.material.transparency {
+            power = power.component_mul(&scene.surface_color(&hit, object));
+            ray = hit.spawn_ray(ray.direction);
+            continue;
+        }
+        if bounces == max_bounces || rng.next_f64() >= shininess {
+            return photons;
+        }
+        let n = hit.surface_normal;
+        let d = ray.direction;
+        ray = hit.spawn_ray(d - n.scale(2.0 * n.dot(&d)));
+        bounces += 1;
+    }
+    photons
+}
+
+#[cfg(test)]
+mod photon_tests {
+    use super::*;
+    use crate::parser::{FileEntry, FileHeader, ProcFile};
+    use std::str::FromStr;
+
+    fn photon(x: f64, y: f64, z: f64) -> Photon {
+        Photon {
+            position: Point3::new(x, y, z),
+            direction: Vector3::new(0.0, -1.0, 0.0),
+            power: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    #[test]
+    fn kd_tree_finds_the_same_photons_as_a_linear_search() {
+        let rng = Rng::new(5);
+        let photons: Vec<Photon> = (0..500)
+            .map(|_| photon(rng.next_f64(), rng.next_f64(), rng.next_f64()))
+            .collect();
+        let tree = KdTree::new(photons.clone());
+        assert_eq!(photons.len(), tree.within(&Point3::origin(), 2.0).len());
+        for _ in 0..20 {
+            let p = Point3::new(rng.next_f64(), rng.next_f64(), rng.next_f64());
+            let expected = photons
+                .iter()
+                .filter(|photon| photon.position.sub(p).magnitude() <= 0.2)
+                .count();
+            assert_eq!(expected, tree.within(&p, 0.2).len());
+        }
+    }
+
+    #[test]
+    fn gather_divides_power_by_area() {
+        let map = PhotonMap::new(vec![photon(0.0, 0.0, 0.0), photon(0.1, 0.0, 0.0)], 1.0);
+        let up = Vector3::new(0.0, 1.0, 0.0);
+        let light = map.gather(&Point3::origin(), &up);
+        assert!((light.x - 2.0 / PI).abs() < 1e-12);
+        // photons landing on the other side of the surface do not light it
+        assert_eq!(Vector3::zeros(), map.gather(&Point3::origin(), &-up));
+    }
+
+    fn scene(lines: &[&str]) -> Scene {
+        let file = ProcFile {
+            header: FileHeader::from_str("png 10 10 photons.png").unwrap(),
+            entries: lines
+                .iter()
+                .map(|l| FileEntry::from_str(l).unwrap())
+                .collect(),
+        };
+        Scene::from_file(&file).unwrap()
+    }
+
+    #[test]
+    fn photons_land_on_the_floor_after_a_mirror() {
+        let scene = scene(&[
+            "bulb 0 4 0",
+            "shininess 1",
+            "sphere 0 1 0 1",
+            "shininess 0",
+            "plane 0 1 0 0",
+        ]);
+        let photons = trace_photons(&scene, &RayTracer::new(&scene), 1000, 4, &Rng::new(3));
+        assert!(!photons.is_empty());
+        assert!(photons.iter().all(|p| p.position.y.abs() < 1e-6));
+    }
+
+    #[test]
+    fn overlapping_mirrors_do_not_add_power() {
+        let stored = |mirrors: &[&str], light: &str| {
+            let lines = [
+                &[light, "shininess 1"],
+                mirrors,
+                &["shininess 0", "plane 0 1 0 0"],
+            ];
+            let scene = scene(&lines.concat());
+            let photons = trace_photons(&scene, &RayTracer::new(&scene), 20000, 4, &Rng::new(6));
+            photons.iter().map(|p| p.power.x).sum::<f64>()
+        };
+        for light in ["bulb 0 4 0", "sun 1 1 0"] {
+            let one = stored(&["sphere 0 1 0 1"], light);
+            // the second sphere hides behind the first, so the same light reaches the floor
+            let two = stored(&["sphere 0 1 0 1", "sphere 0 1 0 0.5"], light);
+            assert!((two - one).abs() < 0.05 * one, "{}: {} {}", light, one, two);
+        }
+    }
+
+    #[test]
+    fn shiny_planes_reflect_photons_onto_the_scene() {
+        let scene = scene(&[
+            "bulb 0 4 0",
+            "shininess 1",
+            "plane 0 1 0 0",
+            "shininess 0",
+            "sphere -3 1 0 0.5",
+            "sphere 3 1 0 0.5",
+        ]);
+        let photons = trace_photons(&scene, &RayTracer::new(&scene), 1000, 4, &Rng::new(3));
+        assert!(!photons.is_empty());
+        let from_center = |p: &Photon| p.position.coords.abs() - Vector3::new(3.0, 1.0, 0.0);
+        assert!(photons
+            .iter()
+            .all(|p| (from_center(p).magnitude() - 0.5).abs() < 1e-6));
+    }
+
+    #[test]
+    fn photons_pass_through_transparent_surfaces_filtered_by_them() {
+        let scene = scene(&[
+            "bulb 0 4 0",
+            "color 1 0 0",
+            "transparency 1",
+            "plane 0 1 0 -3",
+            "color 1 1 1",
+            "transparency 0",
+            "shininess 1",
+            "sphere 0 1 0 1",
+            "shininess 0",
+            "plane 0 1 0 0",
+        ]);
+        let photons = trace_photons(&scene, &RayTracer::new(&scene), 1000, 4, &Rng::new(3));
+        assert!(photons.iter().any(|p| p.position.y.abs() < 1e-6));
+        assert!(photons.iter().all(|p| p.power.y == 0.0 && p.power.z == 0.0));
+    }
+}
@@ -1,11 +1,21 @@
-use crate::models::{ObjPrimative, SceneObject, AABB};
+use crate::csg::Intervals;
+use crate::intersections::{
+    box_crossings, cone_crossings, cylinder_crossings, disk_crossings, nearest, plane_crossings,
+    quadric_crossings, quadric_value, sphere_crossings, torus_crossings, Crossing,
+};
+use crate::models::{Material, ObjPrimative, SceneObject, AABB};
 use crate::scene::{BVHNode, Scene, MAX_OBJECTS};
-use nalgebra::{Point3, Vector3};
+use crate::transform::Transform;
+use crate::utils::{gamma, orthonormal_basis};
+use nalgebra::{Point3, Vector2, Vector3};
+use std::f64::consts::PI;
 use std::ops::{Add, Div, Sub};
 use uuid::Uuid;
 
 const FORCE_BVH: bool = true;
-const MIN_RAY_LENGTH: f64 = 0.0001;
+// rounding operations allowed for in the position of a hit found along a ray, which is
+// generous enough to cover solving for where the ray crosses the surface
+const HIT_OPERATIONS: f64 = 64.0;
 
 pub struct Ray {
     pub origin: Point3<f64>,
@@ -33,7 +43,9 @@ impl Ray {
             tmin = tmin.max(t1);
             tmax = tmax.min(t2);
 
-            if tmax <= tmin {
+            // flat boxes, such as around a single axis aligned triangle, are hit where tmax and
+            // tmin meet
+            if tmax < tmin {
                 return None;
             }
         }
@@ -63,6 +75,13 @@ mod ray_tests {
         assert_eq!(inter, Some(-1.0));
     }
 
+    #[test]
+    pub fn ray_intersects_flat_box() {
+        let r = Ray::new(Point3::new(0.5, 0.5, 1.0), Vector3::new(0.0, 0.0, -1.0));
+        let aabb = AABB::new(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 1.0, 0.0));
+        assert_eq!(r.intersect_aabb(&aabb), Some(1.0));
+    }
+
     #[test]
     pub fn returns_none_if_ray_does_not_intersect_any_object() {
         let r = Ray::new(Point3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0));
@@ -78,6 +97,53 @@ pub struct RayHit {
     pub distance: f64,
     pub object_id: Uuid,
     pub surface_normal: Vector3<f64>,
+    pub uv: Vector2<f64>,
+    // directions in which the texture coordinates u and v increase along the surface
+    pub tangent: Vector3<f64>,
+    pub bitangent: Vector3<f64>,
+    // Normal of the surface itself, unlike `surface_normal` which may be smoothed or bumped.
+    // It points out of solids and to the front of surfaces, where `surface_normal` always
+    // faces back against the ray.
+    pub geometric_normal: Vector3<f64>,
+    // bound on how far `position` may be from the true hit in each axis
+    pub error: Vector3<f64>,
+    // set when the hit was found through an instance
+    pub placement: Option<Placement>,
+}
+
+// How an object hit through instances was placed: the transform from the space of its
+// definition into world space, and the material of the instance overriding its own, if any
+#[derive(Debug, Clone, Copy)]
+pub struct Placement {
+    pub transform: Transform,
+    pub material: Option<Material>,
+}
+
+impl RayHit {
+    // Ray leaving the hit in `direction`. It starts just past the error of the hit position
+    // along the geometric normal, on the side it leaves towards, so that rounding can not make
+    // it hit the same surface again.
+    pub fn spawn_ray(&self, direction: Vector3<f64>) -> Ray {
+        let n = self.geometric_normal;
+        let distance = n.abs().dot(&self.error);
+        let offset = match direction.dot(&n) < 0.0 {
+            true => n.scale(-distance),
+            false => n.scale(distance),
+        };
+        let p = self.position + offset;
+        // adding the offset rounds as well, so each axis is moved a step further away
+        let origin = Point3::from(Vector3::from_fn(|i, _| match offset[i] {
+            o if o > 0.0 => p[i].next_up(),
+            o if o < 0.0 => p[i].next_down(),
+            _ => p[i],
+        }));
+        Ray::new(origin, direction)
+    }
+}
+
+// bound on the error in each axis of the point `t` along a ray
+pub fn hit_error(ray: &Ray, t: f64) -> Vector3<f64> {
+    (ray.origin.coords.abs() + ray.direction.scale(t).abs()).scale(gamma(HIT_OPERATIONS))
 }
 
 // need to perform raytracing given a scene
@@ -92,21 +158,106 @@ fn plane_intersection(
     n: Vector3<f64>,
     p: Point3<f64>,
 ) -> Option<RayHit> {
-    let t = p.sub(ray.origin).dot(&n) / ray.direction.dot(&n);
+    let facing = ray.direction.dot(&n);
+    let t = p.sub(ray.origin).dot(&n) / facing;
     return if t < 0f64 {
         None
     } else {
         let position = ray.origin.add(ray.direction.scale(t));
+        // planar texture coordinates, one unit in the plane is one repeat of the texture
+        let (tangent, bitangent) = orthonormal_basis(&n);
+        let offset = position.sub(p);
         Some(RayHit {
             position,
             direction: ray.direction,
             distance: t,
             object_id: object.id,
-            surface_normal: n,
+            surface_normal: match facing > 0.0 {
+                true => -n,
+                false => n,
+            },
+            uv: Vector2::new(offset.dot(&tangent), offset.dot(&bitangent)),
+            tangent,
+            bitangent,
+            geometric_normal: n,
+            error: hit_error(ray, t),
+            placement: None,
         })
     };
 }
 
+// spherical texture coordinates of a point on a sphere given its outward normal
+pub fn sphere_uv(n: &Vector3<f64>) -> Vector2<f64> {
+    let u = 0.5 + n.x.atan2(n.z) / (2.0 * PI);
+    let v = 0.5 + n.y.clamp(-1.0, 1.0).asin() / PI;
+    Vector2::new(u, v)
+}
+
+// tangent and bitangent matching `sphere_uv`, falling back to any basis at the poles
+pub fn sphere_tangents(n: &Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+    match Vector3::new(n.z, 0.0, -n.x).try_normalize(f64::EPSILON) {
+        Some(t) => (t, n.cross(&t)),
+        None => orthonormal_basis(n),
+    }
+}
+
+// tangent and bitangent of a triangle from how its texture coordinates change along its edges
+fn triangle_tangents(
+    vertices: &[Point3<f64>; 3],
+    uvs: &[Vector2<f64>; 3],
+    n: &Vector3<f64>,
+) -> (Vector3<f64>, Vector3<f64>) {
+    let e1 = vertices[1].sub(vertices[0]);
+    let e2 = vertices[2].sub(vertices[0]);
+    let d1 = uvs[1] - uvs[0];
+    let d2 = uvs[2] - uvs[0];
+    let det = d1.x * d2.y - d2.x * d1.y;
+    if det.abs() < f64::EPSILON {
+        return orthonormal_basis(n);
+    }
+    let t = (e1.scale(d2.y) - e2.scale(d1.y)).scale(1.0 / det);
+    let b = (e2.scale(d1.x) - e1.scale(d2.x)).scale(1.0 / det);
+    match (t.try_normalize(f64::EPSILON), b.try_normalize(f64::EPSILON)) {
+        (Some(t), Some(b)) => (t, b),
+        _ => orthonormal_basis(n),
+    }
+}
+
+// Watertight ray triangle test (Woop, Benthin and Wald 2013). The vertices are sheared into a
+// space where the ray runs along z from the origin, where triangles sharing an edge compute the
+// same function for it, so rays can not slip between them. Returns the distance along the ray
+// and the weight of each vertex at the hit.
+fn triangle_crossing(ray: &Ray, vertices: &[Point3<f64>; 3]) -> Option<(f64, [f64; 3])> {
+    let d = ray.direction;
+    let kz = d.iamax();
+    let (mut kx, mut ky) = ((kz + 1) % 3, (kz + 2) % 3);
+    // keeps the winding of the triangle the same
+    if d[kz] < 0.0 {
+        std::mem::swap(&mut kx, &mut ky);
+    }
+    let (sx, sy, sz) = (d[kx] / d[kz], d[ky] / d[kz], 1.0 / d[kz]);
+    let [a, b, c] = vertices.map(|v| {
+        let p = v.sub(ray.origin);
+        Vector3::new(p[kx] - sx * p[kz], p[ky] - sy * p[kz], sz * p[kz])
+    });
+    // each edge function is the weight of the vertex opposite of the edge
+    let u = c.x * b.y - c.y * b.x;
+    let v = a.x * c.y - a.y * c.x;
+    let w = b.x * a.y - b.y * a.x;
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+    let det = u + v + w;
+    if det == 0.0 {
+        return None;
+    }
+    let t = (u * a.z + v * b.z + w * c.z) / det;
+    match t > 0.0 {
+        true => Some((t, [u / det, v / det, w / det])),
+        false => None,
+    }
+}
+
 impl<'a> RayTracer<'a> {
     pub fn new(scene: &'a Scene) -> Self {
         Self {
@@ -115,7 +266,110 @@ impl<'a> RayTracer<'a> {
         }
     }
 
+    // Intersects a ray with an object. Transformed objects are intersected in object space, with
+    // a ray direction which is left unnormalized so that distances carry over to world space.
     fn find_intersection(&self, ray: &Ray, object: &SceneObject) -> Option<RayHit> {
+        match object.transform {
+            None => self.find_local_intersection(ray, object),
+            Some(transform) => {
+                let local_ray = Ray::new(
+                    transform.inverse_point(&ray.origin),
+                    transform.inverse_vector(&ray.direction),
+                );
+                self.find_local_intersection(&local_ray, object)
+                    .map(|hit| RayHit {
+                        position: transform.point(&hit.position),
+                        error: transform.point_error(&hit.position, &hit.error),
+                        direction: ray.direction,
+                        surface_normal: transform.normal(&hit.surface_normal),
+                        geometric_normal: transform.normal(&hit.geometric_normal),
+                        tangent: transform.vector(&hit.tangent).normalize(),
+                        bitangent: transform.vector(&hit.bitangent).normalize(),
+                        ..hit
+                    })
+            }
+        }
+    }
+
+    // every crossing of a ray with a solid object, which csg combines into crossings of the
+    // combined solid
+    fn solid_intervals(&self, ray: &Ray, object: &SceneObject) -> Intervals {
+        let local_ray = match object.transform {
+            None => Ray::new(ray.origin, ray.direction),
+            Some(transform) => Ray::new(
+                transform.inverse_point(&ray.origin),
+                transform.inverse_vector(&ray.direction),
+            ),
+        };
+        let crossings = match object.primitive {
+            ObjPrimative::Sphere { xyz, r } => sphere_crossings(&local_ray, xyz, r),
+            ObjPrimative::Plane { n, p } => plane_crossings(&local_ray, n, p),
+            ObjPrimative::Cylinder {
+                base,
+                axis,
+                height,
+                r,
+                capped,
+            } => cylinder_crossings(&local_ray, base, axis, height, r, capped),
+            ObjPrimative::Cone {
+                base,
+                axis,
+                height,
+                r,
+                capped,
+            } => cone_crossings(&local_ray, base, axis, height, r, capped),
+            ObjPrimative::Box { min, max } | ObjPrimative::Volume { min, max, .. } => {
+                box_crossings(&local_ray, min, max)
+            }
+            ObjPrimative::Torus {
+                center,
+                axis,
+                major,
+                minor,
+            } => torus_crossings(&local_ray, center, axis, major, minor),
+            ObjPrimative::Quadric { coefficients } => quadric_crossings(&local_ray, &coefficients),
+            ObjPrimative::Csg { node, .. } => {
+                let node = &self.scene.csg[node];
+                return node.combine(
+                    self.solid_intervals(ray, &node.left),
+                    self.solid_intervals(ray, &node.right),
+                );
+            }
+            _ => vec![],
+        };
+        let crossings: Vec<_> = crossings
+            .into_iter()
+            .map(|c| match object.transform {
+                None => (c, object.id),
+                Some(transform) => {
+                    let c = Crossing {
+                        normal: transform.normal(&c.normal),
+                        tangent: transform.vector(&c.tangent).normalize(),
+                        ..c
+                    };
+                    (c, object.id)
+                }
+            })
+            .collect();
+        // a ray which leaves the solid through its first crossing started inside, one which never
+        // crosses it can only be inside of an unbounded solid
+        let starts_inside = match crossings.first() {
+            Some((c, _)) => c.normal.dot(&ray.direction) > 0.0,
+            None => match object.primitive {
+                ObjPrimative::Plane { n, p } => local_ray.origin.sub(p).dot(&n) < 0.0,
+                ObjPrimative::Quadric { coefficients } => {
+                    quadric_value(&coefficients, &local_ray.origin) < 0.0
+                }
+                _ => false,
+            },
+        };
+        Intervals {
+            starts_inside,
+            crossings,
+        }
+    }
+
+    fn find_local_intersection(&self, ray: &Ray, object: &SceneObject) -> Option<RayHit> {
         match object.primitive {
             ObjPrimative::Sphere { xyz, r } => {
                 // check if the ray starts inside the sphere
@@ -123,7 +377,7 @@ impl<'a> RayTracer<'a> {
                 let inside = vec_to_sphere.magnitude_squared() < r.powi(2);
                 let t_c = vec_to_sphere
                     .dot(&ray.direction)
-                    .div(ray.direction.magnitude());
+                    .div(ray.direction.magnitude_squared());
                 if !inside && t_c < 0f64 {
                     return None;
                 }
@@ -145,6 +399,7 @@ impl<'a> RayTracer<'a> {
                 };
                 let intersection: Point3<f64> = ray.origin.add(ray.direction.scale(distance));
                 let surface_normal = intersection.sub(xyz).scale(1.0 / r);
+                let (tangent, bitangent) = sphere_tangents(&surface_normal);
                 Some(RayHit {
                     position: intersection,
                     direction: ray.direction,
@@ -154,95 +409,190 @@ impl<'a> RayTracer<'a> {
                         false => surface_normal,
                         true => -surface_normal,
                     },
+                    uv: sphere_uv(&surface_normal),
+                    tangent,
+                    bitangent,
+                    geometric_normal: surface_normal,
+                    error: hit_error(ray, distance),
+                    placement: None,
                 })
             }
             ObjPrimative::Plane { n, p } => plane_intersection(ray, object, n, p),
+            ObjPrimative::Cylinder {
+                base,
+                axis,
+                height,
+                r,
+                capped,
+            } => nearest(cylinder_crossings(ray, base, axis, height, r, capped))
+                .map(|c| c.into_hit(ray, object)),
+            ObjPrimative::Cone {
+                base,
+                axis,
+                height,
+                r,
+                capped,
+            } => nearest(cone_crossings(ray, base, axis, height, r, capped))
+                .map(|c| c.into_hit(ray, object)),
+            ObjPrimative::Disk { center, n, r } => {
+                nearest(disk_crossings(ray, center, n, r)).map(|c| c.into_hit(ray, object))
+            }
+            ObjPrimative::Box { min, max } | ObjPrimative::Volume { min, max, .. } => {
+                nearest(box_crossings(ray, min, max)).map(|c| c.into_hit(ray, object))
+            }
+            ObjPrimative::Torus {
+                center,
+                axis,
+                major,
+                minor,
+            } => nearest(torus_crossings(ray, center, axis, major, minor))
+                .map(|c| c.into_hit(ray, object)),
+            ObjPrimative::Quadric { coefficients } => {
+                nearest(quadric_crossings(ray, &coefficients)).map(|c| c.into_hit(ray, object))
+            }
+            // hits take the id of the solid they are on, so that it provides the material
+            ObjPrimative::Csg { .. } => self
+                .solid_intervals(ray, object)
+                .crossings
+                .into_iter()
+                .find(|(c, _)| c.t > 0.0)
+                .map(|(c, id)| RayHit {
+                    object_id: id,
+                    ..c.into_hit(ray, object)
+                }),
+            ObjPrimative::Sdf { sdf, .. } => self.scene.sdfs[sdf]
+                .march(ray)
+                .map(|c| c.into_hit(ray, object)),
+            ObjPrimative::Blob { blob, .. } => self.scene.blobs[blob]
+                .intersect(ray)
+                .map(|c| c.into_hit(ray, object)),
+            // hits keep the object of the definition they are on, and remember the placement
+            // which moved it there, which encloses any placement found inside the definition
+            ObjPrimative::Instance {
+                definition,
+                override_material,
+                ..
+            } => {
+                let bvh = &self.scene.definitions[definition].bvh;
+                self.find_intersection_bvh(bvh, ray).map(|hit| {
+                    let transform = object.transform.unwrap_or(Transform::identity());
+                    let inner = hit.placement;
+                    let placement = Placement {
+                        transform: match inner {
+                            Some(p) => transform.then(&p.transform),
+                            None => transform,
+                        },
+                        material: match override_material {
+                            true => Some(object.material),
+                            false => inner.and_then(|p| p.material),
+                        },
+                    };
+                    RayHit {
+                        placement: Some(placement),
+                        ..hit
+                    }
+                })
+            }
             ObjPrimative::Triangle {
                 vertices,
                 n,
-                e1,
-                e2,
+                normals,
+                uvs,
             } => {
-                let intersection = plane_intersection(ray, object, n, vertices[0]);
-                match intersection {
-                    None => None,
-                    Some(mut hit) => {
-                        hit.surface_normal = match n {
-                            a if self.scene.camera_settings.forward.dot(&a) > 0.0 => -a,
-                            a => a,
-                        };
-                        let b1 = e1.dot(&hit.position.sub(vertices[0]));
-                        let b2 = e2.dot(&hit.position.sub(vertices[0]));
-                        let b0 = 1.0 - b1 - b2;
-                        if b0 > 0.0 && b1 > 0.0 && b2 > 0.0 {
-                            Some(hit)
-                        } else {
-                            None
-                        }
-                    }
-                }
+                let (t, [b0, b1, b2]) = triangle_crossing(ray, &vertices)?;
+                let weighted = [
+                    vertices[0].coords.scale(b0),
+                    vertices[1].coords.scale(b1),
+                    vertices[2].coords.scale(b2),
+                ];
+                // turned back to face the ray when it hits the back of the triangle
+                let flip = ray.direction.dot(&n) > 0.0;
+                let shading_normal = match normals {
+                    Some([n0, n1, n2]) => (n0.scale(b0) + n1.scale(b1) + n2.scale(b2))
+                        .try_normalize(f64::EPSILON)
+                        .unwrap_or(n),
+                    None => n,
+                };
+                let (tangent, bitangent) = triangle_tangents(&vertices, &uvs, &n);
+                Some(RayHit {
+                    // found from the vertices rather than along the ray, which is more precise
+                    position: Point3::from(weighted[0] + weighted[1] + weighted[2]),
+                    direction: ray.direction,
+                    distance: t,
+                    object_id: object.id,
+                    surface_normal: match flip {
+                        true => -shading_normal,
+                        false => shading_normal,
+                    },
+                    uv: uvs[0].scale(b0) + uvs[1].scale(b1) + uvs[2].scale(b2),
+                    tangent,
+                    bitangent,
+                    geometric_normal: n,
+                    error: (weighted[0].abs() + weighted[1].abs() + weighted[2].abs())
+                        .scale(gamma(7.0)),
+                    placement: None,
+                })
             }
         }
     }
 
-    fn find_closest_intersection(
-        &self,
-        ray: &Ray,
-        objects: &Vec<SceneObject>,
-        ignore_object_id: Option<Uuid>,
-    ) -> Option<RayHit> {
+    fn find_closest_intersection(&self, ray: &Ray, objects: &Vec<SceneObject>) -> Option<RayHit> {
         let hits: Vec<RayHit> = objects
             .iter()
-            .filter_map(|o| match ignore_object_id {
-                None => self.find_intersection(ray, o),
-                Some(id) if id != o.id => self.find_intersection(ray, o),
-                _ => None,
+            .filter_map(|o| {
+                // back faces of one sided surfaces are culled, which the objects of a definition
+                // have already been unless an instance draws them in its own material
+                let two_sided = match o.primitive {
+                    ObjPrimative::Instance {
+                        override_material: false,
+                        ..
+                    } => true,
+                    _ => o.material.two_sided,
+                };
+                self.find_intersection(ray, o)
+                    .filter(|h| two_sided || h.direction.dot(&h.geometric_normal) < 0.0)
             })
             .collect();
         let result = hits
             .iter()
-            .filter(|&i| i.distance > MIN_RAY_LENGTH)
+            .filter(|&i| i.distance > 0.0)
             .map(|i| i.clone())
             .min_by(|a, b| a.distance.total_cmp(&b.distance));
         return result;
     }
 
-    fn find_intersection_bvh(
-        &self,
-        node: &BVHNode,
-        ray: &Ray,
-        filter_object: Option<Uuid>,
-    ) -> Option<RayHit> {
+    fn find_intersection_bvh(&self, node: &BVHNode, ray: &Ray) -> Option<RayHit> {
         match &node.children {
-            None => {
-                self.find_closest_intersection(ray, &node.bounding_volume.children, filter_object)
-            }
-            Some(children) => {
-                children
-                    .iter()
-                    .filter(|&n| match ray.intersect_aabb(&n.bounding_volume.aabb) {
-                        None => false,
-                        _ => true,
-                    })
-                    .filter_map(|n| self.find_intersection_bvh(n, ray, filter_object))
-                    .min_by(|a, b| a.distance.total_cmp(&b.distance))
-            }
+            None => self.find_closest_intersection(ray, &node.bounding_volume.children),
+            Some(children) => children
+                .iter()
+                .filter(|&n| match ray.intersect_aabb(&n.bounding_volume.aabb) {
+                    None => false,
+                    _ => true,
+                })
+                .filter_map(|n| self.find_intersection_bvh(n, ray))
+                .min_by(|a, b| a.distance.total_cmp(&b.distance)),
         }
     }
 
-    pub fn trace_ray(&self, ray: &Ray, ignore_object_id: Option<Uuid>) -> Option<RayHit> {
+    pub fn trace_ray(&self, ray: &Ray) -> Option<RayHit> {
         if !self.force_bvh && self.scene.objects.len() < MAX_OBJECTS {
-            self.find_closest_intersection(ray, &self.scene.objects, ignore_object_id)
+            self.find_closest_intersection(ray, &self.scene.objects)
         } else {
             let root_inter = ray.intersect_aabb(&self.scene.bvh.bounding_volume.aabb);
             if root_inter.is_some() {
-                self.find_intersection_bvh(&self.scene.bvh, ray, ignore_object_id)
+                self.find_intersection_bvh(&self.scene.bvh, ray)
             } else {
-                let planes = self.scene.objects.iter().filter_map(|&o| match o.primitive {
-                    ObjPrimative::Plane {.. } => Some(o.clone()),
-                    _ => None,
-                }).collect();
-                self.find_closest_intersection(ray, &planes, ignore_object_id)
+                let unbounded = self
+                    .scene
+                    .objects
+                    .iter()
+                    .filter_map(|&o| match o.aabb {
+                        None => Some(o.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                self.find_closest_intersection(ray, &unbounded)
             }
         }
     }
@@ -266,3 +616,230 @@ mod bvh_tests {
         let output = renderer.render_scene().unwrap();
     }
 }
+
+#[cfg(test)]
+mod hit_tests {
+    use super::*;
+    use crate::parser::{FileEntry, FileHeader, ProcFile};
+    use std::str::FromStr;
+
+    fn scene(lines: &[&str]) -> Scene {
+        let file = ProcFile {
+            header: FileHeader::from_str("png 10 10 hit.png").unwrap(),
+            entries: lines
+                .iter()
+                .map(|l| FileEntry::from_str(l).unwrap())
+                .collect(),
+        };
+        Scene::from_file(&file).unwrap()
+    }
+
+    #[test]
+    fn rays_can_not_slip_between_triangles_sharing_an_edge() {
+        let quad = [
+            Point3::new(0.1, 0.3, 0.0),
+            Point3::new(1.7, 0.2, 0.0),
+            Point3::new(1.3, 1.9, 0.0),
+            Point3::new(0.2, 1.1, 0.0),
+        ];
+        let rng = crate::utils::Rng::new(9);
+        for _ in 0..10000 {
+            // somewhere along the shared edge from the first to the third corner
+            let s = rng.next_f64();
+            let target = quad[0] + quad[2].sub(quad[0]).scale(s);
+            let origin = Point3::new(rng.next_f64(), rng.next_f64(), 1.0 + rng.next_f64());
+            let ray = Ray::new(origin, target.sub(origin));
+            let first = triangle_crossing(&ray, &[quad[0], quad[1], quad[2]]);
+            let second = triangle_crossing(&ray, &[quad[0], quad[2], quad[3]]);
+            assert!(first.is_some() || second.is_some());
+        }
+    }
+
+    #[test]
+    fn spawned_rays_do_not_hit_their_own_surface_far_from_the_origin() {
+        let far = scene(&[
+            "sphere 1000000 0 0 1",
+            "xyz 1000005 0 -1",
+            "xyz 1000007 0 -1",
+            "xyz 1000006 2 -1",
+            "trif 1 2 3",
+        ]);
+        let ray_tracer = RayTracer::new(&far);
+        let rng = crate::utils::Rng::new(4);
+        for i in 0..2000 {
+            let jitter = Vector3::new(0.0, rng.next_f64() - 0.5, rng.next_f64() - 0.5);
+            let target = match i % 2 {
+                0 => Point3::new(1000000.0, 0.0, 0.0) + jitter,
+                _ => Point3::new(1000006.0 + jitter.z, 0.5 + jitter.y, -1.0),
+            };
+            let origin = Point3::new(1000003.0, 0.0, 1000.0);
+            let hit = ray_tracer
+                .trace_ray(&Ray::new(origin, target.sub(origin)))
+                .unwrap();
+            // neither surface can be hit again on the way out
+            let n = hit.geometric_normal;
+            let d = hit.direction;
+            let reflected = d - n.scale(2.0 * n.dot(&d));
+            assert!(ray_tracer.trace_ray(&hit.spawn_ray(reflected)).is_none());
+            // and going on into the sphere only hits its far side
+            match ray_tracer.trace_ray(&hit.spawn_ray(d)) {
+                Some(through) => assert!(through.distance * d.magnitude() > 1e-3),
+                None => assert_eq!(1, i % 2),
+            }
+        }
+        // shapes found with the quadratic formula, which loses precision when rays start far
+        // from the shape
+        let shapes = [
+            "cylinder 0 -1 0 0 1 0 1 capped",
+            "cone 0 -1 0 0 1 0 1",
+            "quadric 1 2 1 0 0 0 0 0 0 -1",
+        ];
+        for (shape, distance) in shapes.iter().flat_map(|s| [(s, 10.0), (s, 1000.0)]) {
+            let scene = scene(&[shape]);
+            let ray_tracer = RayTracer::new(&scene);
+            let origin = Point3::new(3.0, 1.0, distance);
+            for _ in 0..2000 {
+                let target = Point3::new(rng.next_f64() - 0.5, rng.next_f64() - 0.5, 0.0);
+                let Some(hit) = ray_tracer.trace_ray(&Ray::new(origin, target.sub(origin))) else {
+                    continue;
+                };
+                let n = hit.geometric_normal;
+                let d = hit.direction;
+                let reflected = d - n.scale(2.0 * n.dot(&d));
+                assert!(
+                    ray_tracer.trace_ray(&hit.spawn_ray(reflected)).is_none(),
+                    "{}",
+                    shape
+                );
+                if let Some(through) = ray_tracer.trace_ray(&hit.spawn_ray(d)) {
+                    assert!(through.distance * d.magnitude() > 1e-3, "{}", shape);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn normals_face_the_ray_on_either_side_of_a_triangle() {
+        let scene = scene(&["xyz -1 -1 0", "xyz 1 -1 0", "xyz 0 1 0", "trif 1 2 3"]);
+        let ray_tracer = RayTracer::new(&scene);
+        for z in [-1.0, 1.0] {
+            let ray = Ray::new(Point3::new(0.0, 0.0, z), Vector3::new(0.0, 0.0, -z));
+            let hit = ray_tracer.trace_ray(&ray).unwrap();
+            assert!(hit.surface_normal.dot(&ray.direction) < 0.0);
+            assert_eq!(Vector3::new(0.0, 0.0, 1.0), hit.geometric_normal);
+        }
+    }
+
+    #[test]
+    fn back_faces_of_one_sided_surfaces_are_culled() {
+        let scene = scene(&[
+            "onesided",
+            "xyz -1 -1 0",
+            "xyz 1 -1 0",
+            "xyz 0 1 0",
+            "trif 1 2 3",
+            "sphere 0 0 -5 1",
+        ]);
+        let ray_tracer = RayTracer::new(&scene);
+        let front = Ray::new(Point3::new(0.0, 0.0, 1.0), Vector3::new(0.0, 0.0, -1.0));
+        assert_eq!(1.0, ray_tracer.trace_ray(&front).unwrap().distance);
+        let back = Ray::new(Point3::new(0.0, 0.0, -1.0), Vector3::new(0.0, 0.0, 1.0));
+        assert!(ray_tracer.trace_ray(&back).is_none());
+        // the inside of a one sided solid is all back faces
+        let inside = Ray::new(Point3::new(0.0, 0.0, -5.0), Vector3::new(0.0, 0.0, 1.0));
+        assert!(ray_tracer.trace_ray(&inside).is_none());
+    }
+
+    #[test]
+    fn instances_keep_the_materials_of_their_definition_unless_overridden() {
+        let scene = scene(&[
+            "define pair",
+            "color 0 1 0",
+            "sphere -1 0 0 0.5",
+            "color 1 1 0",
+            "sphere 1 0 0 0.5",
+            "end",
+            "push",
+            "translate -5 0 0",
+            "color 1 0 0",
+            "instance pair",
+            "pop",
+            "translate 5 0 0",
+            "color 0 0 1",
+            "instance pair material",
+        ]);
+        let ray_tracer = RayTracer::new(&scene);
+        let down = Vector3::new(0.0, -1.0, 0.0);
+        let colors: Vec<Vector3<f64>> = [-6.0, -4.0, 4.0, 6.0]
+            .iter()
+            .map(|&x| {
+                let hit = ray_tracer
+                    .trace_ray(&Ray::new(Point3::new(x, 5.0, 0.0), down))
+                    .unwrap();
+                let object = scene.hit_object(&hit).unwrap();
+                // the top of the sphere, undoing the transform of the instance
+                let local = object.object_space_position(&hit.position);
+                assert!((local - Point3::new(0.0, 0.5, 0.0)).magnitude() < 1e-9);
+                object.material.color
+            })
+            .collect();
+        assert_eq!(Vector3::new(0.0, 1.0, 0.0), colors[0]);
+        assert_eq!(Vector3::new(1.0, 1.0, 0.0), colors[1]);
+        assert_eq!(Vector3::new(0.0, 0.0, 1.0), colors[2]);
+        assert_eq!(Vector3::new(0.0, 0.0, 1.0), colors[3]);
+    }
+
+    #[test]
+    fn tangents_point_along_increasing_texture_coordinates() {
+        let scene = scene(&[
+            "sphere 0 0 0 1",
+            "texcoord 0 0",
+            "xyz 2 -1 0",
+            "texcoord 0 1",
+            "xyz 4 -1 0",
+            "texcoord 1 0",
+            "xyz 2 1 0",
+            "trif 1 2 3",
+        ]);
+        let ray_tracer = RayTracer::new(&scene);
+        let back = Vector3::new(0.0, 0.0, -1.0);
+        let uv_at = |p: Point3<f64>| {
+            let ray = Ray::new(p + Vector3::new(0.0, 0.0, 5.0), back);
+            ray_tracer.trace_ray(&ray).unwrap().uv
+        };
+        for p in [
+            Point3::new(0.3, 0.2, 0.0),
+            Point3::new(-0.5, -0.4, 0.0),
+            Point3::new(2.5, -0.2, 0.0),
+        ] {
+            let hit = ray_tracer
+                .trace_ray(&Ray::new(p + Vector3::new(0.0, 0.0, 5.0), back))
+                .unwrap();
+            let step = 1e-4;
+            let along_t = uv_at(hit.position + hit.tangent.scale(step)) - hit.uv;
+            let along_b = uv_at(hit.position + hit.bitangent.scale(step)) - hit.uv;
+            // u grows along the tangent and v along the bitangent, each leaving the other alone
+            assert!(along_t.x > 0.0 && along_t.y.abs() < 1e-3 * along_t.x);
+            assert!(along_b.y > 0.0 && along_b.x.abs() < 1e-3 * along_b.y);
+        }
+    }
+
+    #[test]
+    fn relative_indices_pick_texture_coordinates_from_their_own_list() {
+        // one more vertex than texture coordinate, so the same index means something else in
+        // each list
+        let scene = scene(&[
+            "xyz 9 9 9",
+            "texcoord 0 0",
+            "xyz 0 0 0",
+            "texcoord 1 0",
+            "xyz 1 0 0",
+            "texcoord 0 1",
+            "xyz 0 1 0",
+            "trif -3 -2 -1",
+        ]);
+        let ray = Ray::new(Point3::new(0.25, 0.5, 1.0), Vector3::new(0.0, 0.0, -1.0));
+        let hit = RayTracer::new(&scene).trace_ray(&ray).unwrap();
+        assert!((hit.uv - Vector2::new(0.25, 0.5)).magnitude() < 1e-9);
+    }
+}
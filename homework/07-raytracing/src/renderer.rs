@@ -1,11 +1,26 @@
 use crate::lighting_models::LightingModel;
+use crate::medium::{combine, Interior, Medium};
+use crate::models::SceneObject;
 use crate::parser::{FileEntry, ProcFile};
+use crate::photons::{trace_photons, PhotonMap};
 use crate::raytracer::{Ray, RayHit, RayTracer};
 use crate::scene::{CameraSettings, Scene};
-use crate::utils::{vec3_add_alpha, BLACK};
-use nalgebra::{Vector3, Vector4};
+use crate::texture::BumpMap;
+use crate::utils::{orthonormal_basis, vec3_add_alpha, Rng, BLACK};
+use crate::volume::Volume;
+use nalgebra::{Vector2, Vector3, Vector4};
+use std::f64::consts::PI;
 use std::ops::Add;
 
+// points along a ray through a medium at which light scattered towards the eye is gathered
+const MEDIUM_SAMPLES: usize = 16;
+// walks taken through a volume for every ray which crosses it
+const VOLUME_SAMPLES: usize = 16;
+// paths carrying less of the light than this are thinned out by russian roulette
+const ROULETTE_THROUGHPUT: f64 = 0.1;
+// highest chance of going on past the bounce limit, so that every path ends
+const MAX_SURVIVAL: f64 = 0.95;
+
 #[derive(Debug)]
 pub struct RendererOutput {
     pub pixel_buffer: Vec<Vec<Option<Vector3<f64>>>>,
@@ -37,6 +52,17 @@ pub struct Renderer<'a> {
     options: RendererOptions,
     ray_tracer: RayTracer<'a>,
     lighting_model: LightingModel<'a>,
+    // jitters the points sampled in media, which turns banding into noise
+    rng: Rng,
+    photon_map: Option<PhotonMap>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AmbientOcclusion {
+    samples: usize,
+    distance: f64,
+    // shade with the occlusion alone instead of using it to darken the lights
+    only: bool,
 }
 
 struct RendererOptions {
@@ -44,6 +70,8 @@ struct RendererOptions {
     height: usize,
     max_depth: usize,
     aa: usize,
+    photons: Option<(usize, f64)>,
+    ao: Option<AmbientOcclusion>,
 }
 
 impl RendererOptions {
@@ -58,12 +86,30 @@ impl RendererOptions {
         };
 
         let aa = file.get_aa();
+        let photons = file.entries.iter().find_map(|entry| match entry {
+            FileEntry::Photons { count, radius } => Some((*count, *radius)),
+            _ => None,
+        });
+        let ao = file.entries.iter().find_map(|entry| match entry {
+            FileEntry::Ao {
+                samples,
+                distance,
+                only,
+            } => Some(AmbientOcclusion {
+                samples: *samples,
+                distance: *distance,
+                only: *only,
+            }),
+            _ => None,
+        });
 
         Ok(RendererOptions {
             width: file.header.width as usize,
             height: file.header.height as usize,
             max_depth,
             aa,
+            photons,
+            ao,
         })
     }
 }
@@ -99,55 +145,278 @@ impl<'a> Renderer<'a> {
         let options = RendererOptions::from_file(file)?;
         let ray_tracer: RayTracer<'_> = RayTracer::new(&scene);
         let lighting_model = LightingModel::from_file(&file, scene);
+        let rng = Rng::new(0);
+        // caustics are traced once, before any rays are cast from the eye
+        let photon_map = options.photons.map(|(count, radius)| {
+            let photons = trace_photons(scene, &ray_tracer, count, options.max_depth, &rng);
+            PhotonMap::new(photons, radius)
+        });
         Ok(Self {
             scene,
             options,
             ray_tracer,
             lighting_model,
+            rng,
+            photon_map,
         })
     }
 
+    // moves the shading normal of a hit according to the material's normal or bump map
+    fn apply_bump(&self, hit: RayHit, object: &SceneObject) -> RayHit {
+        let bump = match object.material.bump {
+            Some(b) => b,
+            None => return hit,
+        };
+        let n = hit.surface_normal;
+        // tangent frame around the shading normal
+        let t = match (hit.tangent - n.scale(n.dot(&hit.tangent))).try_normalize(f64::EPSILON) {
+            Some(t) => t,
+            None => return hit,
+        };
+        let b = match hit.bitangent.dot(&n.cross(&t)) < 0.0 {
+            true => -n.cross(&t),
+            false => n.cross(&t),
+        };
+        let perturbed = match bump {
+            BumpMap::Normal(texture) => {
+                let m = self.scene.textures[texture]
+                    .sample(&hit.uv)
+                    .map(|c| 2.0 * c - 1.0);
+                t.scale(m.x) + b.scale(m.y) + n.scale(m.z)
+            }
+            BumpMap::Height { texture, strength } => {
+                let texture = &self.scene.textures[texture];
+                let step = texture.texel_size();
+                let height = |du: f64, dv: f64| texture.sample(&(hit.uv + Vector2::new(du, dv))).x;
+                let dh_du = (height(step.x, 0.0) - height(-step.x, 0.0)) / (2.0 * step.x);
+                let dh_dv = (height(0.0, step.y) - height(0.0, -step.y)) / (2.0 * step.y);
+                n - (t.scale(dh_du) + b.scale(dh_dv)).scale(strength)
+            }
+        };
+        match perturbed.try_normalize(f64::EPSILON) {
+            Some(surface_normal) => RayHit {
+                surface_normal,
+                ..hit
+            },
+            None => hit,
+        }
+    }
+
     // return the lit value at this position
     fn light(&self, hit: &RayHit) -> Vector3<f64> {
-        let material = self.scene.get_object(hit.object_id).unwrap().material;
-        let light = self.lighting_model.light(&hit);
-        material.color.component_mul(&light)
+        let object = &self.scene.hit_object(hit).unwrap();
+        let mut light = self.lighting_model.light(&hit);
+        if let Some(photon_map) = &self.photon_map {
+            light += photon_map.gather(&hit.position, &hit.surface_normal);
+        }
+        self.scene.surface_color(hit, object).component_mul(&light)
     }
 
-    fn get_recast_ray(&self, hit: &RayHit, depth: usize) -> Vector3<f64> {
+    // Fraction of the hemisphere above a hit, facing the ray, which is open for at least the
+    // occlusion distance. Rays are spread by the cosine of their angle to the normal, as light
+    // from directly above a surface lights it most. Media and volumes do not occlude.
+    fn ambient_visibility(&self, hit: &RayHit, ao: &AmbientOcclusion) -> f64 {
+        if ao.samples == 0 {
+            return 1.0;
+        }
+        let n = match hit.surface_normal.dot(&hit.direction) > 0.0 {
+            true => -hit.surface_normal,
+            false => hit.surface_normal,
+        };
+        let (u, v) = orthonormal_basis(&n);
+        let open = (0..ao.samples)
+            .filter(|_| {
+                let r = self.rng.next_f64().sqrt();
+                let phi = 2.0 * PI * self.rng.next_f64();
+                let direction = u.scale(r * phi.cos())
+                    + v.scale(r * phi.sin())
+                    + n.scale((1.0 - r * r).max(0.0).sqrt());
+                match self.ray_tracer.trace_ray(&hit.spawn_ray(direction)) {
+                    Some(h) if h.distance < ao.distance => {
+                        let object = &self.scene.hit_object(&h).unwrap();
+                        self.scene.interior(object).is_some()
+                    }
+                    _ => true,
+                }
+            })
+            .count();
+        open as f64 / ao.samples as f64
+    }
+
+    fn get_recast_ray(
+        &self,
+        hit: &RayHit,
+        depth: usize,
+        throughput: f64,
+        inside: Option<Interior>,
+    ) -> Vector3<f64> {
         let i = hit.direction;
         let n = hit.surface_normal;
         let d = i - (2.0 * n.dot(&i) * n);
-        let new_ray = Ray::new(hit.position, d);
-        match self.cast_ray(&new_ray, depth + 1) {
-            Some(new_hit) => {
-                if new_hit.x == 0.0 && new_hit.y == 0.0 && new_hit.z == 0.0 {
-                    self.cast_ray(&new_ray, depth + 1);
-                }
-                new_hit
-            }
+        let new_ray = hit.spawn_ray(d);
+        match self.continue_path(&new_ray, depth + 1, throughput, inside) {
+            Some(new_hit) => new_hit,
             None => BLACK,
         }
     }
 
-    fn cast_ray(&self, ray: &Ray, depth: usize) -> Option<Vector3<f64>> {
-        if depth > self.options.max_depth {
+    // Follows a path on from a surface, carrying `throughput` of the light which reaches the
+    // eye along it. Russian roulette stops dim paths at random, and past the bounce limit every
+    // path, so that mirrors facing each other still end. The paths which go on are weighed up
+    // by the chance of stopping, which leaves the image the same on average.
+    fn continue_path(
+        &self,
+        ray: &Ray,
+        depth: usize,
+        throughput: f64,
+        inside: Option<Interior>,
+    ) -> Option<Vector3<f64>> {
+        let survival = match depth > self.options.max_depth {
+            true => throughput.min(MAX_SURVIVAL),
+            false => (throughput / ROULETTE_THROUGHPUT).min(1.0),
+        };
+        if survival < 1.0 && self.rng.next_f64() >= survival {
+            return None;
+        }
+        self.cast_ray(ray, depth, throughput / survival, inside)
+            .map(|c| c.scale(1.0 / survival))
+    }
+
+    // Light scattered towards the start of the ray over its first `distance`. Samples are
+    // spread evenly over the light that the medium takes out of the ray rather than over the
+    // distance, so that they bunch up where most of the scattering happens.
+    fn in_scattering(&self, ray: &Ray, distance: f64, medium: &Medium) -> Vector3<f64> {
+        let scattered = 1.0 - medium.transmittance(distance);
+        let offset = self.rng.next_f64();
+        let gathered: Vector3<f64> = (0..MEDIUM_SAMPLES)
+            .map(|i| {
+                let u = (i as f64 + offset) / MEDIUM_SAMPLES as f64;
+                let s = medium.distance_to(1.0 - u * scattered);
+                let position = ray.origin + ray.direction.scale(s);
+                self.lighting_model.scattered_light(&position)
+            })
+            .sum();
+        medium
+            .color
+            .component_mul(&gathered)
+            .scale(scattered / MEDIUM_SAMPLES as f64)
+    }
+
+    // Delta tracking through a volume up to `distance`. Each walk either ends where it collides
+    // with the volume, and gathers the light scattered there, or reaches what is `behind` it.
+    // Empty space stays empty if no walk collides.
+    fn through_volume(
+        &self,
+        ray: &Ray,
+        distance: f64,
+        volume: &Volume,
+        behind: Option<Vector3<f64>>,
+    ) -> Option<Vector3<f64>> {
+        let mut gathered = Vector3::zeros();
+        let mut passed = 0;
+        for _ in 0..VOLUME_SAMPLES {
+            match volume.sample_collision(ray, 0.0, distance, &self.rng) {
+                Some(t) => {
+                    let position = ray.origin + ray.direction.scale(t);
+                    gathered += self.lighting_model.scattered_light(&position);
+                }
+                None => passed += 1,
+            }
+        }
+        if behind.is_none() && passed == VOLUME_SAMPLES {
             return None;
         }
-        match self.ray_tracer.trace_ray(ray, None) {
+        let scattered = volume.color.component_mul(&gathered);
+        let behind = behind.unwrap_or(BLACK).scale(passed as f64);
+        Some((scattered + behind).scale(1.0 / VOLUME_SAMPLES as f64))
+    }
+
+    // `inside` is what fills the object the ray starts in, if anything. Rays are taken to leave
+    // it at the first surface they cross on the way out, so media and volumes do not nest.
+    fn cast_ray(
+        &self,
+        ray: &Ray,
+        depth: usize,
+        throughput: f64,
+        inside: Option<Interior>,
+    ) -> Option<Vector3<f64>> {
+        let hit = self.ray_tracer.trace_ray(ray);
+        let distance = match &hit {
+            Some(hit) => hit.distance,
+            None => f64::INFINITY,
+        };
+        let surface = match hit {
             Some(hit) => {
-                let material = self.scene.get_object(hit.object_id).unwrap().material;
-                match material.shininess {
-                    s if s == 0.0 => Some(self.light(&hit)),
-                    s if s == 1.0 => Some(self.get_recast_ray(&hit, depth)),
-                    s => {
-                        let lit = self.light(&hit).scale(1.0 - s);
-                        let bounced = self.get_recast_ray(&hit, depth).scale(s);
-                        Some(lit + bounced)
+                let object = &self.scene.hit_object(&hit).unwrap();
+                let material = object.material;
+                match self.scene.interior(object) {
+                    // the ray passes through, into or out of the medium
+                    Some(interior) => {
+                        let next = match ray.direction.dot(&hit.geometric_normal) < 0.0 {
+                            true => Some(interior),
+                            false => None,
+                        };
+                        self.cast_ray(&hit.spawn_ray(ray.direction), depth, throughput, next)
+                    }
+                    None => {
+                        let hit = self.apply_bump(hit, object);
+                        // occlusion only darkens what is seen directly from the eye
+                        let visibility = match (self.options.ao, depth) {
+                            (Some(ao), 0) => self.ambient_visibility(&hit, &ao),
+                            _ => 1.0,
+                        };
+                        if let Some(AmbientOcclusion { only: true, .. }) = self.options.ao {
+                            return Some(Vector3::new(visibility, visibility, visibility));
+                        }
+                        // share of the path's light which is seen in the surface rather than
+                        // through it
+                        let kept = throughput * (1.0 - material.transparency);
+                        let shaded = match material.shininess {
+                            s if s == 0.0 => Some(self.light(&hit).scale(visibility)),
+                            s if s == 1.0 => Some(self.get_recast_ray(&hit, depth, kept, inside)),
+                            s => {
+                                let lit = self.light(&hit).scale((1.0 - s) * visibility);
+                                let bounced =
+                                    self.get_recast_ray(&hit, depth, kept * s, inside).scale(s);
+                                Some(lit + bounced)
+                            }
+                        };
+                        match material.transparency {
+                            0.0 => shaded,
+                            // what is behind shows through, tinted by the surface
+                            t => {
+                                let tint = self.scene.surface_color(&hit, object);
+                                let through = hit.spawn_ray(ray.direction);
+                                let passed = throughput * t * tint.abs().max();
+                                let behind = self
+                                    .continue_path(&through, depth + 1, passed, inside)
+                                    .unwrap_or(BLACK);
+                                let shaded = shaded.unwrap_or(BLACK).scale(1.0 - t);
+                                Some(shaded + tint.component_mul(&behind).scale(t))
+                            }
+                        }
                     }
                 }
             }
             None => None,
+        };
+        let (surface, medium) = match inside {
+            Some(Interior::Volume(v)) => {
+                let volume = &self.scene.volumes[v];
+                (self.through_volume(ray, distance, volume, surface), None)
+            }
+            Some(Interior::Medium(m)) => (surface, Some(m)),
+            None => (surface, None),
+        };
+        match combine(self.scene.fog, medium) {
+            Some(medium) => {
+                let scattered = self.in_scattering(ray, distance, &medium);
+                let behind = surface
+                    .unwrap_or(BLACK)
+                    .scale(medium.transmittance(distance));
+                Some(scattered + behind)
+            }
+            None => surface,
         }
     }
 
@@ -159,7 +428,7 @@ impl<'a> Renderer<'a> {
         );
 
         for (ray, (x, y)) in rays.iter() {
-            match self.cast_ray(ray, 0) {
+            match self.cast_ray(ray, 0, 1.0, None) {
                 Some(color) => {
                     output.pixel_buffer[*y][*x] = Some(color);
                     // vec3_to_rgb(&color.map(|c| match self.options.exposure {
@@ -174,3 +443,155 @@ impl<'a> Renderer<'a> {
         Ok(output)
     }
 }
+
+#[cfg(test)]
+mod renderer_tests {
+    use super::*;
+    use crate::models::Material;
+    use crate::parser::FileHeader;
+    use crate::texture::{Texture, WrapMode};
+    use nalgebra::Point3;
+    use std::str::FromStr;
+
+    fn file(lines: &[&str]) -> ProcFile {
+        ProcFile {
+            header: FileHeader::from_str("png 10 10 renderer.png").unwrap(),
+            entries: lines
+                .iter()
+                .map(|l| FileEntry::from_str(l).unwrap())
+                .collect(),
+        }
+    }
+
+    // A triangle facing +z, with u growing along x and v along y, and a hit looking down on it
+    // through a material with the given bump map. The textures are added to the scene.
+    fn bumped_hit(bump: BumpMap, textures: Vec<Texture>) -> (RayHit, RayHit) {
+        let file = file(&[
+            "texcoord 0 0",
+            "xyz 0 0 0",
+            "texcoord 1 0",
+            "xyz 1 0 0",
+            "texcoord 0 1",
+            "xyz 0 1 0",
+            "trif 1 2 3",
+        ]);
+        let mut scene = Scene::from_file(&file).unwrap();
+        scene.textures = textures;
+        let renderer = Renderer::from_file(&file, &scene).unwrap();
+        let ray = Ray::new(Point3::new(0.25, 0.25, 1.0), Vector3::new(0.0, 0.0, -1.0));
+        let hit = renderer.ray_tracer.trace_ray(&ray).unwrap();
+        let object = scene.get_object(hit.object_id).unwrap();
+        let bumped = SceneObject {
+            material: Material {
+                bump: Some(bump),
+                ..object.material
+            },
+            ..*object
+        };
+        (hit.clone(), renderer.apply_bump(hit, &bumped))
+    }
+
+    #[test]
+    fn flat_normal_maps_leave_the_normal_alone() {
+        let flat = Texture {
+            width: 1,
+            height: 1,
+            texels: vec![Vector3::new(0.5, 0.5, 1.0)],
+            wrap: WrapMode::Repeat,
+        };
+        let (hit, bumped) = bumped_hit(BumpMap::Normal(0), vec![flat]);
+        assert_eq!(Vector3::new(0.0, 0.0, 1.0), hit.surface_normal);
+        assert!((bumped.surface_normal - hit.surface_normal).magnitude() < 1e-12);
+    }
+
+    #[test]
+    fn bump_maps_tilt_the_normal_away_from_rising_heights() {
+        // heights rise with u, which runs along x
+        let ramp = Texture {
+            width: 2,
+            height: 1,
+            texels: vec![Vector3::zeros(), Vector3::new(1.0, 1.0, 1.0)],
+            wrap: WrapMode::Clamp,
+        };
+        let bump = BumpMap::Height {
+            texture: 0,
+            strength: 0.5,
+        };
+        let (_, bumped) = bumped_hit(bump, vec![ramp]);
+        let n = bumped.surface_normal;
+        assert!(n.x < -0.1 && n.z > 0.0);
+        assert!(n.y.abs() < 1e-12);
+        assert!((n.magnitude() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn russian_roulette_leaves_dim_reflections_the_same_on_average() {
+        let file = file(&[
+            "sun 0 1 1",
+            "shininess 0.05",
+            "plane 0 1 0 0",
+            "shininess 0",
+            "plane 0 0 1 5",
+        ]);
+        let scene = Scene::from_file(&file).unwrap();
+        let renderer = Renderer::from_file(&file, &scene).unwrap();
+        let ray = Ray::new(Point3::new(0.0, 1.0, 5.0), Vector3::new(0.0, -1.0, -5.0));
+        // the floor is lit directly and reflects the wall, which is only lit directly
+        let hit = renderer.ray_tracer.trace_ray(&ray).unwrap();
+        let reflected = Ray::new(hit.position, Vector3::new(0.0, 1.0, -5.0));
+        let wall = renderer.cast_ray(&reflected, 1, 1.0, None).unwrap();
+        let expected = renderer.light(&hit).scale(0.95) + wall.scale(0.05);
+        let runs = 20000;
+        let results: Vec<Vector3<f64>> = (0..runs)
+            .map(|_| renderer.cast_ray(&ray, 0, 1.0, None).unwrap())
+            .collect();
+        // some reflections are stopped, and those which are not make up for them
+        assert!(results.iter().any(|r| (r - expected).magnitude() > 1e-3));
+        let average = results
+            .iter()
+            .sum::<Vector3<f64>>()
+            .scale(1.0 / runs as f64);
+        assert!((average - expected).magnitude() < 0.01 * expected.magnitude());
+    }
+
+    // ray looking straight down at the floor at `p`
+    fn down_at(p: Point3<f64>) -> Ray {
+        Ray::new(
+            p + Vector3::new(0.0, 1.0, 0.0),
+            Vector3::new(0.0, -1.0, 0.0),
+        )
+    }
+
+    fn visibility(lines: &[&str], p: Point3<f64>) -> f64 {
+        let file = file(lines);
+        let scene = Scene::from_file(&file).unwrap();
+        let renderer = Renderer::from_file(&file, &scene).unwrap();
+        let hit = renderer.ray_tracer.trace_ray(&down_at(p)).unwrap();
+        renderer.ambient_visibility(&hit, &renderer.options.ao.unwrap())
+    }
+
+    fn color(lines: &[&str], p: Point3<f64>) -> Vector3<f64> {
+        let file = file(lines);
+        let scene = Scene::from_file(&file).unwrap();
+        let renderer = Renderer::from_file(&file, &scene).unwrap();
+        renderer.cast_ray(&down_at(p), 0, 1.0, None).unwrap()
+    }
+
+    #[test]
+    fn inside_corners_are_occluded_and_open_floors_are_not() {
+        let p = Point3::new(0.0, 0.0, 0.1);
+        assert!(visibility(&["ao 200 1", "plane 0 1 0 0", "plane 0 0 1 0"], p) < 0.9);
+        assert_eq!(1.0, visibility(&["ao 200 1", "plane 0 1 0 0"], p));
+    }
+
+    #[test]
+    fn occlusion_alone_is_drawn_in_grey_without_lights() {
+        let corner = Point3::new(0.0, 0.0, 0.3);
+        let lines = ["ao 64 1 only", "plane 0 1 0 0", "plane 0 0 1 0"];
+        let grey = color(&lines, corner);
+        assert!(grey.x > 0.0 && grey.x < 1.0);
+        assert_eq!(Vector3::repeat(grey.x), grey);
+        // the same scene lit by nothing is black
+        assert_eq!(BLACK, color(&lines[1..], corner));
+    }
+}
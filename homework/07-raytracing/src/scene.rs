@@ -1,9 +1,21 @@
+use crate::blob::{Ball, Blob};
+use crate::csg::CsgNode;
+use crate::fractals::{menger, sphereflake};
+use crate::medium::{Interior, Medium};
+use crate::mesh::{Mesh, MeshOptions, DEFAULT_MESH_OPTIONS};
 use crate::models::{
-    LightPrimitive, LightSourceObject, Material, ObjPrimative, SceneObject, AABB, DEFAULT_COLOR,
-    DEFAULT_MATERIAL,
+    Falloff, LightPrimitive, LightSourceObject, Material, ObjPrimative, SceneObject, AABB,
+    DEFAULT_COLOR, DEFAULT_MATERIAL,
 };
 use crate::parser::{FileEntry, ProcFile};
-use nalgebra::{Point3, Vector3};
+use crate::pattern::Pattern;
+use crate::raytracer::RayHit;
+use crate::sdf::Sdf;
+use crate::terrain::terrain;
+use crate::texture::{BumpMap, Displacement, DisplacementSource, Texture, WrapMode};
+use crate::transform::Transform;
+use crate::volume::Volume;
+use nalgebra::{Point3, Vector2, Vector3};
 use std::ops::{Div, Sub};
 use uuid::Uuid;
 
@@ -154,32 +166,172 @@ const DEFAULT_CAMERA_SETTINGS: CameraSettings = CameraSettings {
     up: Vector3::new(0.0, 1.0, 0.0),
 };
 
+// Objects from a `define` block, shared by every instance of it
+#[derive(Debug)]
+pub struct Definition {
+    pub name: String,
+    pub objects: Vec<SceneObject>,
+    pub bvh: BVHNode,
+}
+
+impl Definition {
+    fn new(name: String, objects: Vec<SceneObject>) -> Self {
+        let bvh = BVHNode::from_objects(objects.clone());
+        Self { name, objects, bvh }
+    }
+
+    // bounds of every object in the definition, or none if any object is unbounded
+    fn bounds(&self) -> Option<AABB> {
+        match self.objects.iter().all(|o| o.aabb.is_some()) {
+            true => Some(self.bvh.bounding_volume.aabb),
+            false => None,
+        }
+    }
+}
+
+// what is saved while the objects of a `define` block are being read
+struct DefinitionState {
+    name: String,
+    objects: Vec<SceneObject>,
+    transform: Transform,
+    transform_stack: Vec<Transform>,
+}
+
+// what is saved while the balls of a `blob` block are being read
+struct BlobState {
+    blob: Blob,
+    material: Material,
+    transform: Transform,
+}
+
 #[derive(Debug)]
 pub struct Scene {
     pub camera_settings: CameraSettings,
     pub light_sources: Vec<LightSourceObject>,
     pub objects: Vec<SceneObject>,
+    pub textures: Vec<Texture>,
+    pub definitions: Vec<Definition>,
+    pub csg: Vec<CsgNode>,
+    pub sdfs: Vec<Sdf>,
+    pub blobs: Vec<Blob>,
+    pub volumes: Vec<Volume>,
+    // fills all of space, including the inside of objects
+    pub fog: Option<Medium>,
     pub bvh: BVHNode,
 }
 
-fn get_vertex(i: i32, v: &Vec<Point3<f64>>) -> Point3<f64> {
+fn get_vertex_index(i: i32, v: &Vec<Point3<f64>>) -> usize {
     let neg: bool = i < 0;
-    let ind: usize = match neg {
+    match neg {
         true => v.len() - usize::try_from(i * -1).unwrap(),
         false => usize::try_from(i - 1).unwrap(),
+    }
+}
+
+// Texture coordinate with the same index as a vertex. It is counted in the list of texture
+// coordinates on its own, so that relative indices count back from the last `texcoord`.
+fn get_texcoord_index(i: i32, t: &[Vector2<f64>]) -> Option<usize> {
+    let index = match i < 0 {
+        true => t.len().checked_sub(i.unsigned_abs() as usize),
+        false => usize::try_from(i - 1).ok(),
     };
-    v[ind]
+    index.filter(|&i| i < t.len())
+}
+
+// preprocesses the pending mesh with the given options and moves its triangles into the scene
+fn flush_mesh(
+    mesh: &mut Mesh,
+    options: &MeshOptions,
+    textures: &[Texture],
+    objects: &mut Vec<SceneObject>,
+) {
+    if !mesh.is_empty() {
+        objects.extend(mesh.take().into_objects(options, textures));
+    }
 }
 
 impl Scene {
+    // what fills an object which light passes into rather than bouncing off, if anything
+    pub fn interior(&self, object: &SceneObject) -> Option<Interior> {
+        match (object.primitive, object.material.medium) {
+            (ObjPrimative::Volume { volume, .. }, _) => Some(Interior::Volume(volume)),
+            (_, Some(medium)) => Some(Interior::Medium(medium)),
+            _ => None,
+        }
+    }
+
+    // The object a hit lies on, as it was placed. Objects hit through instances are moved by
+    // their transforms, and take the material of any instance which overrides it.
+    pub fn hit_object(&self, hit: &RayHit) -> Option<SceneObject> {
+        let object = *self.get_object(hit.object_id)?;
+        Some(match hit.placement {
+            None => object,
+            Some(placement) => SceneObject {
+                material: placement.material.unwrap_or(object.material),
+                transform: Some(match object.transform {
+                    Some(t) => placement.transform.then(&t),
+                    None => placement.transform,
+                }),
+                ..object
+            },
+        })
+    }
+
+    // the unlit color of a surface at this position
+    pub fn surface_color(&self, hit: &RayHit, object: &SceneObject) -> Vector3<f64> {
+        let material = object.material;
+        match (material.texture, material.pattern) {
+            (Some(t), _) => self.textures[t].sample(&hit.uv),
+            (None, Some(pattern)) => {
+                pattern.color(&object.pattern_position(&pattern, &hit.position))
+            }
+            (None, None) => material.color,
+        }
+    }
+
     pub fn get_object(&self, id: Uuid) -> Option<&SceneObject> {
         match self.objects.iter().find(|&o| o.id == id) {
             Some(o) => Some(&o),
-            None => None,
+            // objects hit through an instance live in its definition, and the solids making up
+            // a csg object in its node
+            None => self
+                .definitions
+                .iter()
+                .find_map(|d| d.objects.iter().find(|&o| o.id == id))
+                .or_else(|| {
+                    self.csg
+                        .iter()
+                        .flat_map(|n| [&n.left, &n.right])
+                        .find(|&o| o.id == id)
+                }),
         }
     }
 }
 
+// adds a signed distance shape to the scene and returns the object which draws it
+fn push_sdf(
+    sdfs: &mut Vec<Sdf>,
+    sdf: Sdf,
+    transform: Transform,
+    material: Material,
+) -> SceneObject {
+    let sdf = Sdf::transformed(sdf, transform);
+    let primitive = ObjPrimative::Sdf {
+        sdf: sdfs.len(),
+        bounds: sdf.aabb(),
+    };
+    sdfs.push(sdf);
+    SceneObject::new(primitive, material)
+}
+
+// takes the last object out of the scene so that its shape can be combined into another one
+fn pop_sdf(objects: &mut Vec<SceneObject>, sdfs: &[Sdf]) -> Result<Sdf, String> {
+    match objects.pop().map(|o| o.primitive) {
+        Some(ObjPrimative::Sdf { sdf, .. }) => Ok(sdfs[sdf].clone()),
+        _ => Err("sdfblend and sdfrepeat need signed distance shapes to combine".to_string()),
+    }
+}
+
 impl Scene {
     pub fn from_file(file: &ProcFile) -> Result<Self, String> {
         let mut camera_settings = DEFAULT_CAMERA_SETTINGS;
@@ -187,7 +339,23 @@ impl Scene {
         let mut light_sources: Vec<LightSourceObject> = vec![];
         let mut material: Material = DEFAULT_MATERIAL;
         let mut color: Vector3<f64> = DEFAULT_COLOR;
+        let mut intensity = 1.0;
+        let mut falloff = Falloff::Quadratic;
         let mut vertices: Vec<Point3<f64>> = vec![];
+        let mut texcoords: Vec<Vector2<f64>> = vec![];
+        let mut textures: Vec<Texture> = vec![];
+        let mut mesh = Mesh::new();
+        let mut mesh_options: MeshOptions = DEFAULT_MESH_OPTIONS;
+        let mut transform = Transform::identity();
+        let mut transform_stack: Vec<Transform> = vec![];
+        let mut definitions: Vec<Definition> = vec![];
+        let mut defining: Option<DefinitionState> = None;
+        let mut csg: Vec<CsgNode> = vec![];
+        let mut sdfs: Vec<Sdf> = vec![];
+        let mut blobs: Vec<Blob> = vec![];
+        let mut building_blob: Option<BlobState> = None;
+        let mut volumes: Vec<Volume> = vec![];
+        let mut fog: Option<Medium> = None;
 
         for entry in &file.entries {
             match entry {
@@ -197,7 +365,77 @@ impl Scene {
                         xyz: Point3::<f64>::new(*x, *y, *z),
                         r: *r,
                     };
-                    objects.push(SceneObject::new(primitive, material.clone()));
+                    objects.push(SceneObject::transformed(primitive, material, transform));
+                }
+                FileEntry::Cylinder { a, b, r, capped } | FileEntry::Cone { a, b, r, capped } => {
+                    let base = Point3::from(*a);
+                    let axis = Point3::from(*b).sub(base);
+                    let (axis, height) = match axis.try_normalize(f64::EPSILON) {
+                        Some(n) => (n, axis.magnitude()),
+                        None => {
+                            return Err("Cylinders and cones need two distinct points".to_string())
+                        }
+                    };
+                    let primitive = match entry {
+                        FileEntry::Cylinder { .. } => ObjPrimative::Cylinder {
+                            base,
+                            axis,
+                            height,
+                            r: *r,
+                            capped: *capped,
+                        },
+                        _ => ObjPrimative::Cone {
+                            base,
+                            axis,
+                            height,
+                            r: *r,
+                            capped: *capped,
+                        },
+                    };
+                    objects.push(SceneObject::transformed(primitive, material, transform));
+                }
+                FileEntry::Disk { xyz, n, r } => {
+                    let n = match Vector3::from(*n).try_normalize(f64::EPSILON) {
+                        Some(n) => n,
+                        None => return Err("Cannot create a disk without a normal".to_string()),
+                    };
+                    let primitive = ObjPrimative::Disk {
+                        center: Point3::from(*xyz),
+                        n,
+                        r: *r,
+                    };
+                    objects.push(SceneObject::transformed(primitive, material, transform));
+                }
+                FileEntry::Box { a, b } => {
+                    let primitive = ObjPrimative::Box {
+                        min: Point3::from(*a).inf(&Point3::from(*b)),
+                        max: Point3::from(*a).sup(&Point3::from(*b)),
+                    };
+                    objects.push(SceneObject::transformed(primitive, material, transform));
+                }
+                FileEntry::Torus {
+                    xyz,
+                    axis,
+                    major,
+                    minor,
+                } => {
+                    let axis = match Vector3::from(*axis).try_normalize(f64::EPSILON) {
+                        Some(a) => a,
+                        None => return Err("Cannot create a torus without an axis".to_string()),
+                    };
+                    let primitive = ObjPrimative::Torus {
+                        center: Point3::from(*xyz),
+                        axis,
+                        major: *major,
+                        minor: *minor,
+                    };
+                    objects.push(SceneObject::transformed(primitive, material, transform));
+                }
+                FileEntry::Quadric { coefficients } => {
+                    let primitive = ObjPrimative::Quadric {
+                        coefficients: *coefficients,
+                    };
+                    objects.push(SceneObject::transformed(primitive, material, transform));
                 }
                 FileEntry::Plane { a, b, c, d } => {
                     let n = Vector3::new(*a, *b, *c).normalize();
@@ -207,42 +445,294 @@ impl Scene {
                         (_, _, &c) if c != 0.0 => Point3::new(0.0, 0.0, -d.div(c)),
                         _ => panic!("Cannot create a plane without a normal"),
                     };
-                    let primitive = ObjPrimative::Plane { n, p };
+                    // planes stay planes under any affine transform, so it is applied directly
+                    let primitive = ObjPrimative::Plane {
+                        n: transform.normal(&n),
+                        p: transform.point(&p),
+                    };
                     objects.push(SceneObject::new(primitive, material.clone()));
                 }
+                // transforms, each applies to objects in their own space before the current one
+                FileEntry::Translate { x, y, z } => {
+                    flush_mesh(&mut mesh, &mesh_options, &textures, &mut objects);
+                    transform = transform.then(&Transform::translation(Vector3::new(*x, *y, *z)));
+                    mesh.transform = transform;
+                }
+                FileEntry::Rotate { x, y, z, degrees } => {
+                    flush_mesh(&mut mesh, &mesh_options, &textures, &mut objects);
+                    let rotation = Transform::rotation(Vector3::new(*x, *y, *z), *degrees)?;
+                    transform = transform.then(&rotation);
+                    mesh.transform = transform;
+                }
+                FileEntry::Scale { x, y, z } => {
+                    flush_mesh(&mut mesh, &mesh_options, &textures, &mut objects);
+                    transform = transform.then(&Transform::scaling(Vector3::new(*x, *y, *z))?);
+                    mesh.transform = transform;
+                }
+                FileEntry::Push => {
+                    transform_stack.push(transform);
+                }
+                // instancing, a definition is read with a fresh transform and its own objects
+                FileEntry::Define { name } => {
+                    if defining.is_some() {
+                        return Err(format!("Cannot define {} inside another definition", name));
+                    }
+                    flush_mesh(&mut mesh, &mesh_options, &textures, &mut objects);
+                    defining = Some(DefinitionState {
+                        name: name.to_string(),
+                        objects: std::mem::take(&mut objects),
+                        transform,
+                        transform_stack: std::mem::take(&mut transform_stack),
+                    });
+                    transform = Transform::identity();
+                    mesh.transform = transform;
+                }
+                FileEntry::Terrain {
+                    size,
+                    resolution,
+                    iterations,
+                    seed,
+                } => {
+                    objects.extend(terrain(
+                        *size,
+                        *resolution,
+                        *iterations,
+                        *seed,
+                        material,
+                        transform,
+                        &textures,
+                    ));
+                }
+                // fractals around the unit sphere or cube, placed with the current transform
+                FileEntry::Sphereflake { depth } => {
+                    objects.extend(sphereflake(*depth, material, transform));
+                }
+                FileEntry::Menger { depth } => {
+                    objects.extend(menger(*depth, material, transform));
+                }
+                // metaballs, drawn with the material and transform from the start of the block
+                FileEntry::Blob { threshold } => {
+                    if building_blob.is_some() {
+                        return Err("Cannot start a blob inside another blob".to_string());
+                    }
+                    building_blob = Some(BlobState {
+                        blob: Blob::new(*threshold),
+                        material,
+                        transform,
+                    });
+                }
+                FileEntry::Ball { xyz, r, weight } => match building_blob.as_mut() {
+                    Some(state) => state.blob.balls.push(Ball {
+                        center: Point3::from(*xyz),
+                        radius: *r,
+                        weight: *weight,
+                    }),
+                    None => return Err("ball outside of a blob".to_string()),
+                },
+                FileEntry::End if building_blob.is_some() => {
+                    let state = building_blob.take().unwrap();
+                    if state.blob.balls.is_empty() {
+                        return Err("blob without any balls".to_string());
+                    }
+                    let primitive = ObjPrimative::Blob {
+                        blob: blobs.len(),
+                        bounds: state.blob.aabb(),
+                    };
+                    blobs.push(state.blob);
+                    objects.push(SceneObject::transformed(
+                        primitive,
+                        state.material,
+                        state.transform,
+                    ));
+                }
+                FileEntry::End => {
+                    let state = match defining.take() {
+                        Some(s) => s,
+                        None => return Err("end without a matching define".to_string()),
+                    };
+                    flush_mesh(&mut mesh, &mesh_options, &textures, &mut objects);
+                    let definition_objects = std::mem::replace(&mut objects, state.objects);
+                    definitions.push(Definition::new(state.name, definition_objects));
+                    transform = state.transform;
+                    transform_stack = state.transform_stack;
+                    mesh.transform = transform;
+                }
+                FileEntry::Instance {
+                    name,
+                    material: override_material,
+                } => {
+                    let definition = match definitions.iter().position(|d| d.name == *name) {
+                        Some(d) => d,
+                        None => return Err(format!("Unknown definition: {}", name)),
+                    };
+                    let primitive = ObjPrimative::Instance {
+                        definition,
+                        bounds: definitions[definition].bounds(),
+                        override_material: *override_material,
+                    };
+                    objects.push(SceneObject::transformed(primitive, material, transform));
+                }
+                // combines the last two objects, which stay in the csg node instead of the scene
+                FileEntry::Csg { operation } => {
+                    flush_mesh(&mut mesh, &mesh_options, &textures, &mut objects);
+                    let (right, left) = match (objects.pop(), objects.pop()) {
+                        (Some(r), Some(l)) => (r, l),
+                        _ => return Err("csg needs two objects to combine".to_string()),
+                    };
+                    if !left.is_solid() || !right.is_solid() {
+                        return Err("csg can only combine solid objects".to_string());
+                    }
+                    let node = CsgNode {
+                        operation: *operation,
+                        left,
+                        right,
+                    };
+                    let primitive = ObjPrimative::Csg {
+                        node: csg.len(),
+                        bounds: node.aabb(),
+                    };
+                    csg.push(node);
+                    objects.push(SceneObject::new(primitive, material));
+                }
+                // signed distance shapes have the current transform folded into them
+                FileEntry::SdfBox { xyz, half, radius } => {
+                    let sdf = Sdf::RoundedBox {
+                        center: Point3::from(*xyz),
+                        half: Vector3::from(*half),
+                        radius: *radius,
+                    };
+                    objects.push(push_sdf(&mut sdfs, sdf, transform, material));
+                }
+                FileEntry::SdfCapsule { a, b, r } => {
+                    let sdf = Sdf::Capsule {
+                        a: Point3::from(*a),
+                        b: Point3::from(*b),
+                        r: *r,
+                    };
+                    objects.push(push_sdf(&mut sdfs, sdf, transform, material));
+                }
+                FileEntry::SdfTorus { xyz, major, minor } => {
+                    let sdf = Sdf::Torus {
+                        center: Point3::from(*xyz),
+                        major: *major,
+                        minor: *minor,
+                    };
+                    objects.push(push_sdf(&mut sdfs, sdf, transform, material));
+                }
+                FileEntry::Volume { file, a, b, scale } => {
+                    let (resolution, densities) = Volume::load(file)?;
+                    let densities = densities.into_iter().map(|d| d * scale).collect();
+                    let (a, b) = (Point3::from(*a), Point3::from(*b));
+                    let (min, max) = (a.inf(&b), a.sup(&b));
+                    volumes.push(Volume::new(
+                        resolution,
+                        densities,
+                        (min, max),
+                        material.color,
+                        transform,
+                    )?);
+                    let primitive = ObjPrimative::Volume {
+                        volume: volumes.len() - 1,
+                        min,
+                        max,
+                    };
+                    objects.push(SceneObject::transformed(primitive, material, transform));
+                }
+                // these combine the last one or two shapes, and are not transformed again
+                FileEntry::SdfBlend { k } => {
+                    let b = pop_sdf(&mut objects, &sdfs)?;
+                    let a = pop_sdf(&mut objects, &sdfs)?;
+                    let sdf = Sdf::Blend {
+                        a: Box::new(a),
+                        b: Box::new(b),
+                        k: *k,
+                    };
+                    objects.push(push_sdf(&mut sdfs, sdf, Transform::identity(), material));
+                }
+                FileEntry::SdfRepeat { spacing, count } => {
+                    let sdf = Sdf::Repeat {
+                        shape: Box::new(pop_sdf(&mut objects, &sdfs)?),
+                        spacing: Vector3::from(*spacing),
+                        count: *count,
+                    };
+                    objects.push(push_sdf(&mut sdfs, sdf, Transform::identity(), material));
+                }
+                FileEntry::Pop => {
+                    flush_mesh(&mut mesh, &mesh_options, &textures, &mut objects);
+                    transform = match transform_stack.pop() {
+                        Some(t) => t,
+                        None => return Err("pop without a matching push".to_string()),
+                    };
+                    mesh.transform = transform;
+                }
                 FileEntry::Xyz { x, y, z } => {
                     vertices.push(Point3::new(*x, *y, *z));
                 }
+                FileEntry::Texcoord { u, v } => {
+                    texcoords.push(Vector2::new(*u, *v));
+                }
                 FileEntry::Triangle { a, b, c } => {
-                    let p1 = get_vertex(*a, &vertices);
-                    let p2 = get_vertex(*b, &vertices);
-                    let p3 = get_vertex(*c, &vertices);
-                    let vertices: [Point3<f64>; 3] = [p1, p2, p3];
-                    let n = vertices[1]
-                        .sub(vertices[0])
-                        .cross(&vertices[2].sub(vertices[1]))
-                        .normalize();
-                    let a1 = vertices[2].sub(vertices[0]).cross(&n);
-                    let a2 = vertices[1].sub(vertices[0]).cross(&n);
-                    let e1 = a1.scale(1.0 / a1.dot(&vertices[1].sub(vertices[0])));
-                    let e2 = a2.scale(1.0 / a2.dot(&vertices[2].sub(vertices[0])));
-                    let primitive = ObjPrimative::Triangle {
-                        vertices,
-                        n,
-                        e1,
-                        e2,
+                    let indices = [*a, *b, *c].map(|i| get_vertex_index(i, &vertices));
+                    let uvs = [*a, *b, *c].map(|i| get_texcoord_index(i, &texcoords));
+                    mesh.add_face(indices, uvs, &vertices, &texcoords, material);
+                }
+                FileEntry::Quad { a, b, c, d } => {
+                    let indices = [*a, *b, *c, *d].map(|i| get_vertex_index(i, &vertices));
+                    let uvs = [*a, *b, *c, *d].map(|i| get_texcoord_index(i, &texcoords));
+                    mesh.add_quad(indices, uvs, &vertices, &texcoords, material);
+                }
+                FileEntry::Crease { a, b } => {
+                    let indices = [*a, *b].map(|i| get_vertex_index(i, &vertices));
+                    let uvs = [*a, *b].map(|i| get_texcoord_index(i, &texcoords));
+                    mesh.add_crease(indices, uvs, &vertices, &texcoords);
+                }
+                // mesh preprocessing, applied to every triangle until the option changes again
+                FileEntry::Smooth { angle } => {
+                    flush_mesh(&mut mesh, &mesh_options, &textures, &mut objects);
+                    mesh_options.smooth = match angle {
+                        a if *a > 0.0 => Some(*a),
+                        _ => None,
+                    };
+                }
+                FileEntry::Weld { tolerance } => {
+                    flush_mesh(&mut mesh, &mesh_options, &textures, &mut objects);
+                    mesh_options.weld = match tolerance {
+                        t if *t > 0.0 => Some(*t),
+                        _ => None,
+                    };
+                }
+                FileEntry::Subdivide { levels } => {
+                    flush_mesh(&mut mesh, &mesh_options, &textures, &mut objects);
+                    mesh_options.subdivide = match levels {
+                        0 => None,
+                        l => Some(*l),
                     };
-                    objects.push(SceneObject::new(primitive, material.clone()));
                 }
                 // lighting
                 FileEntry::Sun { x, y, z } => {
                     let light_source =
                         LightPrimitive::Directional(Vector3::new(*x, *y, *z).normalize());
-                    light_sources.push(LightSourceObject::new(light_source, color));
+                    light_sources.push(LightSourceObject::new(
+                        light_source,
+                        color,
+                        intensity,
+                        falloff,
+                    ));
                 }
                 FileEntry::Bulb { x, y, z } => {
                     let light_source = LightPrimitive::Point(Point3::new(*x, *y, *z));
-                    light_sources.push(LightSourceObject::new(light_source, color));
+                    light_sources.push(LightSourceObject::new(
+                        light_source,
+                        color,
+                        intensity,
+                        falloff,
+                    ));
+                }
+                FileEntry::Intensity { i } => {
+                    intensity = *i;
+                }
+                FileEntry::Falloff { falloff: f } => {
+                    falloff = *f;
                 }
                 // Materials
                 FileEntry::Color { r, g, b } => {
@@ -252,6 +742,96 @@ impl Scene {
                 FileEntry::Shiny { s } => {
                     material.shininess = *s;
                 }
+                FileEntry::Transparency { t } => {
+                    material.transparency = *t;
+                }
+                FileEntry::OneSided => {
+                    material.two_sided = false;
+                }
+                FileEntry::TwoSided => {
+                    material.two_sided = true;
+                }
+                // a texture replaces any pattern and vice versa, `texture none` clears both
+                FileEntry::Texture { file, wrap } => {
+                    material.pattern = None;
+                    material.texture = match file {
+                        Some(f) => {
+                            textures.push(Texture::load(f, *wrap, true)?);
+                            Some(textures.len() - 1)
+                        }
+                        None => None,
+                    };
+                }
+                // normal and bump maps replace each other
+                FileEntry::NormalMap { file } => {
+                    material.bump = match file {
+                        Some(f) => {
+                            textures.push(Texture::load(f, WrapMode::Repeat, false)?);
+                            Some(BumpMap::Normal(textures.len() - 1))
+                        }
+                        None => None,
+                    };
+                }
+                FileEntry::BumpMap { file, strength } => {
+                    material.bump = match file {
+                        Some(f) => {
+                            textures.push(Texture::load(f, WrapMode::Repeat, false)?);
+                            Some(BumpMap::Height {
+                                texture: textures.len() - 1,
+                                strength: *strength,
+                            })
+                        }
+                        None => None,
+                    };
+                }
+                FileEntry::Displace {
+                    source,
+                    amount,
+                    levels,
+                    scale,
+                } => {
+                    let source = match source.as_deref() {
+                        None => None,
+                        Some("perlin") => Some(DisplacementSource::Perlin { scale: *scale }),
+                        Some("turbulence") => {
+                            Some(DisplacementSource::Turbulence { scale: *scale })
+                        }
+                        Some(f) => {
+                            textures.push(Texture::load(f, WrapMode::Repeat, false)?);
+                            Some(DisplacementSource::Texture(textures.len() - 1))
+                        }
+                    };
+                    material.displacement = source.map(|source| Displacement {
+                        source,
+                        amount: *amount,
+                        levels: *levels,
+                    });
+                }
+                FileEntry::Medium { density, color } => {
+                    material.medium = match density {
+                        d if *d > 0.0 => Some(Medium {
+                            density: *d,
+                            color: Vector3::from(*color),
+                        }),
+                        _ => None,
+                    };
+                }
+                FileEntry::Pattern {
+                    kind,
+                    scale,
+                    a,
+                    b,
+                    space,
+                } => {
+                    material.texture = None;
+                    material.pattern = Some(Pattern {
+                        kind: *kind,
+                        scale: *scale,
+                        a: Vector3::from(*a),
+                        b: Vector3::from(*b),
+                        space: *space,
+                    });
+                }
                 // settings
                 FileEntry::Eye { x, y, z } => {
                     let eye = Point3::new(*x, *y, *z);
@@ -266,6 +846,15 @@ impl Scene {
                 //     camera_settings.forward = forward;
                 //     camera_settings.right = right;
                 // }
+                FileEntry::Fog { density, color } => {
+                    fog = match density {
+                        d if *d > 0.0 => Some(Medium {
+                            density: *d,
+                            color: Vector3::from(*color),
+                        }),
+                        _ => None,
+                    };
+                }
                 FileEntry::Up { x, y, z } => {
                     let up = Vector3::new(*x, *y, *z);
                     let right = camera_settings.forward.cross(&up);
@@ -275,11 +864,25 @@ impl Scene {
                 _ => {}
             };
         }
+        if building_blob.is_some() {
+            return Err("Blob is missing its end".to_string());
+        }
+        if let Some(state) = defining {
+            return Err(format!("Definition {} is missing its end", state.name));
+        }
+        flush_mesh(&mut mesh, &mesh_options, &textures, &mut objects);
         let bvh = BVHNode::from_objects(objects.clone());
         Ok(Self {
             objects,
             light_sources,
             camera_settings,
+            textures,
+            definitions,
+            csg,
+            sdfs,
+            blobs,
+            volumes,
+            fog,
             bvh,
         })
     }
@@ -0,0 +1,270 @@
+use crate::intersections::{box_crossings, Crossing};
+use crate::models::AABB;
+use crate::raytracer::Ray;
+use crate::transform::Transform;
+use crate::utils::orthonormal_basis;
+use nalgebra::{Point3, Vector2, Vector3};
+use std::ops::Sub;
+
+const MAX_STEPS: usize = 256;
+// how close to the surface a march has to get to count as a hit
+const HIT_DISTANCE: f64 = 1e-5;
+const NORMAL_STEP: f64 = 1e-5;
+
+// A shape given by its signed distance function, negative inside of it
+#[derive(Debug, Clone)]
+pub enum Sdf {
+    // box with its edges rounded off by `radius`, which is included in the half extents
+    RoundedBox {
+        center: Point3<f64>,
+        half: Vector3<f64>,
+        radius: f64,
+    },
+    // all points within `r` of the segment from `a` to `b`
+    Capsule {
+        a: Point3<f64>,
+        b: Point3<f64>,
+        r: f64,
+    },
+    // torus around the y axis
+    Torus {
+        center: Point3<f64>,
+        major: f64,
+        minor: f64,
+    },
+    // union which rounds off the seam between the shapes over a distance of about `k`
+    Blend {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+        k: f64,
+    },
+    // copies of the shape every `spacing`, `count` times along each axis
+    Repeat {
+        shape: Box<Sdf>,
+        spacing: Vector3<f64>,
+        count: [usize; 3],
+    },
+    // Shape in object space. Distances are scaled by how much the transform shrinks space at
+    // most, so that they never overestimate the true distance.
+    Transformed {
+        shape: Box<Sdf>,
+        transform: Box<Transform>,
+        scale: f64,
+    },
+}
+
+// polynomial smooth minimum
+fn smooth_min(a: f64, b: f64, k: f64) -> f64 {
+    if k <= 0.0 {
+        return a.min(b);
+    }
+    let h = (0.5 + 0.5 * (b - a) / k).clamp(0.0, 1.0);
+    b + (a - b) * h - k * h * (1.0 - h)
+}
+
+impl Sdf {
+    pub fn transformed(shape: Sdf, transform: Transform) -> Sdf {
+        if transform.is_identity() {
+            return shape;
+        }
+        let scale = transform
+            .matrix
+            .fixed_view::<3, 3>(0, 0)
+            .into_owned()
+            .singular_values()
+            .min();
+        Sdf::Transformed {
+            shape: Box::new(shape),
+            transform: Box::new(transform),
+            scale,
+        }
+    }
+
+    pub fn distance(&self, p: &Point3<f64>) -> f64 {
+        match self {
+            Sdf::RoundedBox {
+                center,
+                half,
+                radius,
+            } => {
+                let q = p.sub(center).abs() - half.add_scalar(-radius);
+                q.sup(&Vector3::zeros()).magnitude() + q.max().min(0.0) - radius
+            }
+            Sdf::Capsule { a, b, r } => {
+                let ab = b.sub(a);
+                let ap = p.sub(a);
+                let h = (ap.dot(&ab) / ab.magnitude_squared()).clamp(0.0, 1.0);
+                (ap - ab.scale(h)).magnitude() - r
+            }
+            Sdf::Torus {
+                center,
+                major,
+                minor,
+            } => {
+                let q = p.sub(center);
+                Vector2::new(q.xz().magnitude() - major, q.y).magnitude() - minor
+            }
+            Sdf::Blend { a, b, k } => smooth_min(a.distance(p), b.distance(p), *k),
+            Sdf::Repeat {
+                shape,
+                spacing,
+                count,
+            } => {
+                // moves the point into the nearest copy, counting copies from the original
+                let aabb = shape.aabb();
+                let start = aabb.min + aabb.max.sub(aabb.min).scale(0.5);
+                let mut q = *p;
+                for i in 0..3 {
+                    if spacing[i] != 0.0 {
+                        let cell = ((p[i] - start[i]) / spacing[i])
+                            .round()
+                            .clamp(0.0, count[i] as f64 - 1.0);
+                        q[i] -= spacing[i] * cell;
+                    }
+                }
+                shape.distance(&q)
+            }
+            Sdf::Transformed {
+                shape,
+                transform,
+                scale,
+            } => shape.distance(&transform.inverse_point(p)) * scale,
+        }
+    }
+
+    pub fn aabb(&self) -> AABB {
+        match self {
+            Sdf::RoundedBox { center, half, .. } => AABB::new(center - half, center + half),
+            Sdf::Capsule { a, b, r } => {
+                let r = Vector3::new(*r, *r, *r);
+                AABB::new(a.inf(b) - r, a.sup(b) + r)
+            }
+            Sdf::Torus {
+                center,
+                major,
+                minor,
+            } => {
+                let extent = Vector3::new(major + minor, *minor, major + minor);
+                AABB::new(center - extent, center + extent)
+            }
+            Sdf::Blend { a, b, k } => {
+                // the smooth minimum is at most a quarter of `k` below the true minimum
+                let grow = Vector3::new(1.0, 1.0, 1.0).scale(k.max(0.0) / 4.0);
+                let aabb = a.aabb().union(&b.aabb());
+                AABB::new(aabb.min - grow, aabb.max + grow)
+            }
+            Sdf::Repeat {
+                shape,
+                spacing,
+                count,
+            } => {
+                let aabb = shape.aabb();
+                let last = Vector3::from_fn(|i, _| spacing[i] * (count[i] as f64 - 1.0));
+                aabb.union(&AABB::new(aabb.min + last, aabb.max + last))
+            }
+            Sdf::Transformed {
+                shape, transform, ..
+            } => transform.aabb(&shape.aabb()),
+        }
+    }
+
+    // outward normal from the gradient of the distance, found with central differences
+    fn normal(&self, p: &Point3<f64>) -> Vector3<f64> {
+        let gradient = Vector3::from_fn(|i, _| {
+            let mut offset = Vector3::zeros();
+            offset[i] = NORMAL_STEP;
+            self.distance(&(p + offset)) - self.distance(&(p - offset))
+        });
+        gradient
+            .try_normalize(f64::EPSILON)
+            .unwrap_or(Vector3::new(0.0, 1.0, 0.0))
+    }
+
+    // Sphere traces the ray through the shape's bounding box. The march steps by the absolute
+    // distance, so it also finds the way out of the shape for rays which start inside it.
+    pub fn march(&self, ray: &Ray) -> Option<Crossing> {
+        let aabb = self.aabb();
+        let bounds = box_crossings(ray, aabb.min, aabb.max);
+        if bounds.len() < 2 || bounds[1].t < 0.0 {
+            return None;
+        }
+        let speed = ray.direction.magnitude();
+        let mut t = bounds[0].t.max(0.0);
+        // a ray leaving the surface starts within the hit distance of it, so it has to get clear
+        // of the surface before it can hit it again
+        let mut clear = t > 0.0;
+        for _ in 0..MAX_STEPS {
+            if t > bounds[1].t {
+                return None;
+            }
+            let p = ray.origin + ray.direction.scale(t);
+            let d = self.distance(&p).abs();
+            clear = clear || d >= HIT_DISTANCE;
+            if d < HIT_DISTANCE && clear {
+                let normal = self.normal(&p);
+                let (tangent, _) = orthonormal_basis(&normal);
+                return Some(Crossing {
+                    t,
+                    normal,
+                    // sdfs have no natural parameterization, so they are textured from above
+                    uv: Vector2::new(p.x, p.z),
+                    tangent,
+                });
+            }
+            t += d.max(HIT_DISTANCE) / speed;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod sdf_tests {
+    use super::*;
+
+    fn capsule() -> Sdf {
+        Sdf::Capsule {
+            a: Point3::origin(),
+            b: Point3::new(0.0, 2.0, 0.0),
+            r: 0.5,
+        }
+    }
+
+    #[test]
+    fn capsule_distance_is_to_the_segment() {
+        let c = capsule();
+        assert!((c.distance(&Point3::new(1.0, 1.0, 0.0)) - 0.5).abs() < 1e-9);
+        assert!((c.distance(&Point3::new(0.0, 3.0, 0.0)) - 0.5).abs() < 1e-9);
+        assert!((c.distance(&Point3::new(0.0, 1.0, 0.0)) + 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rounded_box_is_inside_its_half_extents() {
+        let b = Sdf::RoundedBox {
+            center: Point3::origin(),
+            half: Vector3::new(1.0, 1.0, 1.0),
+            radius: 0.25,
+        };
+        assert!(b.distance(&Point3::new(1.0, 0.0, 0.0)).abs() < 1e-9);
+        // the corner is rounded off
+        assert!(b.distance(&Point3::new(1.0, 1.0, 1.0)) > 0.0);
+    }
+
+    #[test]
+    fn repeat_copies_the_shape_a_limited_number_of_times() {
+        let r = Sdf::Repeat {
+            shape: Box::new(capsule()),
+            spacing: Vector3::new(3.0, 0.0, 0.0),
+            count: [2, 1, 1],
+        };
+        assert!((r.distance(&Point3::new(4.0, 1.0, 0.0)) - 0.5).abs() < 1e-9);
+        assert!((r.distance(&Point3::new(7.0, 1.0, 0.0)) - 3.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn march_stops_on_the_surface() {
+        let ray = Ray::new(Point3::new(-5.0, 1.0, 0.0), Vector3::new(1.0, 0.0, 0.0));
+        let hit = capsule().march(&ray).unwrap();
+        assert!((hit.t - 4.5).abs() < 1e-4);
+        assert!((hit.normal - Vector3::new(-1.0, 0.0, 0.0)).magnitude() < 1e-4);
+    }
+}
@@ -0,0 +1,204 @@
+// Real roots of a x^2 + b x + c = 0 in ascending order. Uses the numerically stable form which
+// avoids subtracting two nearly equal numbers.
+pub fn solve_quadratic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    if a.abs() < f64::EPSILON {
+        return match b.abs() < f64::EPSILON {
+            true => vec![],
+            false => vec![-c / b],
+        };
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return vec![];
+    }
+    let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+    let (r1, r2) = match q == 0.0 {
+        true => (0.0, 0.0),
+        false => (q / a, c / q),
+    };
+    match r1 < r2 {
+        true => vec![r1, r2],
+        false => vec![r2, r1],
+    }
+}
+
+// Real roots of x^3 + a x^2 + b x + c = 0, in no particular order
+fn solve_monic_cubic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    // substitute x = y - a / 3 to get y^3 + p y + q = 0
+    let shift = a / 3.0;
+    let p = b - a * shift;
+    let q = 2.0 * shift.powi(3) - b * shift + c;
+    let discriminant = (q / 2.0).powi(2) + (p / 3.0).powi(3);
+    let roots = if p.abs() < f64::EPSILON {
+        vec![(-q).cbrt()]
+    } else if discriminant > 0.0 {
+        let s = discriminant.sqrt();
+        vec![(-q / 2.0 + s).cbrt() + (-q / 2.0 - s).cbrt()]
+    } else {
+        // three real roots, found with the trigonometric method
+        let m = 2.0 * (-p / 3.0).sqrt();
+        let theta = (3.0 * q / (p * m)).clamp(-1.0, 1.0).acos() / 3.0;
+        (0..3)
+            .map(|k| m * (theta - 2.0 * std::f64::consts::PI * k as f64 / 3.0).cos())
+            .collect()
+    };
+    roots.into_iter().map(|y| y - shift).collect()
+}
+
+// Real roots of a x^3 + b x^2 + c x + d = 0 in ascending order, refined with newton's method.
+// A leading coefficient too small to matter against the others leaves a quadratic.
+pub fn solve_cubic(a: f64, b: f64, c: f64, d: f64) -> Vec<f64> {
+    if negligible(a, &[b, c, d]) {
+        return solve_quadratic(b, c, d);
+    }
+    let coefficients = [1.0, b / a, c / a, d / a];
+    let mut roots: Vec<f64> = solve_monic_cubic(b / a, c / a, d / a)
+        .into_iter()
+        .map(|x| polish(&coefficients, x))
+        .collect();
+    roots.sort_by(|x, y| x.total_cmp(y));
+    roots
+}
+
+// whether a leading coefficient is lost in rounding next to the largest of the others
+fn negligible(leading: f64, others: &[f64]) -> bool {
+    let largest = others.iter().fold(0.0, |m: f64, c| m.max(c.abs()));
+    leading.abs() <= f64::EPSILON * largest
+}
+
+fn evaluate(coefficients: &[f64], x: f64) -> f64 {
+    coefficients.iter().fold(0.0, |sum, c| sum * x + c)
+}
+
+fn derivative(coefficients: &[f64]) -> Vec<f64> {
+    let degree = coefficients.len() - 1;
+    coefficients[..degree]
+        .iter()
+        .enumerate()
+        .map(|(i, c)| c * (degree - i) as f64)
+        .collect()
+}
+
+// a few newton steps to win back the precision lost by the closed form solution
+fn polish(coefficients: &[f64], mut x: f64) -> f64 {
+    let slope = derivative(coefficients);
+    for _ in 0..4 {
+        let d = evaluate(&slope, x);
+        if d == 0.0 {
+            break;
+        }
+        let next = x - evaluate(coefficients, x) / d;
+        if !next.is_finite() {
+            break;
+        }
+        x = next;
+    }
+    x
+}
+
+// Real roots of a x^4 + b x^3 + c x^2 + d x + e = 0 in ascending order, found with Ferrari's
+// method and then refined with newton's method. A leading coefficient too small to matter
+// against the others leaves a cubic.
+pub fn solve_quartic(a: f64, b: f64, c: f64, d: f64, e: f64) -> Vec<f64> {
+    if negligible(a, &[b, c, d, e]) {
+        return solve_cubic(b, c, d, e);
+    }
+    let (b, c, d, e) = (b / a, c / a, d / a, e / a);
+    // substitute x = y - b / 4 to get y^4 + p y^2 + q y + r = 0
+    let shift = b / 4.0;
+    let p = c - 6.0 * shift * shift;
+    let q = d - 2.0 * c * shift + 8.0 * shift.powi(3);
+    let r = e - d * shift + c * shift * shift - 3.0 * shift.powi(4);
+    let mut roots: Vec<f64> = if q.abs() < 1e-12 {
+        // biquadratic, solve for y^2
+        solve_quadratic(1.0, p, r)
+            .into_iter()
+            .filter(|&y2| y2 >= 0.0)
+            .flat_map(|y2| [-y2.sqrt(), y2.sqrt()])
+            .collect()
+    } else {
+        // split into two quadratics using the largest root of the resolvent cubic
+        let m = solve_monic_cubic(2.0 * p, p * p - 4.0 * r, -q * q)
+            .into_iter()
+            .fold(f64::NEG_INFINITY, f64::max);
+        if m <= 0.0 {
+            return vec![];
+        }
+        let s = m.sqrt();
+        let mut roots = solve_quadratic(1.0, s, (p + m) / 2.0 - q / (2.0 * s));
+        roots.extend(solve_quadratic(1.0, -s, (p + m) / 2.0 + q / (2.0 * s)));
+        roots
+    };
+    let coefficients = [1.0, b, c, d, e];
+    roots = roots
+        .into_iter()
+        .map(|y| polish(&coefficients, y - shift))
+        .collect();
+    roots.sort_by(|x, y| x.total_cmp(y));
+    roots
+}
+
+#[cfg(test)]
+mod solver_tests {
+    use super::*;
+
+    #[test]
+    fn quadratic_roots_are_sorted() {
+        assert_eq!(vec![-3.0, 2.0], solve_quadratic(1.0, 1.0, -6.0));
+        assert_eq!(vec![-3.0, 2.0], solve_quadratic(-1.0, -1.0, 6.0));
+    }
+
+    #[test]
+    fn quadratic_without_real_roots_is_empty() {
+        assert!(solve_quadratic(1.0, 0.0, 1.0).is_empty());
+    }
+
+    #[test]
+    fn degenerate_quadratic_is_linear() {
+        assert_eq!(vec![2.0], solve_quadratic(0.0, 2.0, -4.0));
+    }
+
+    fn assert_roots(expected: &[f64], actual: Vec<f64>) {
+        assert_eq!(expected.len(), actual.len(), "{:?}", actual);
+        for (e, a) in expected.iter().zip(actual) {
+            assert!((e - a).abs() < 1e-9, "{} != {}", e, a);
+        }
+    }
+
+    #[test]
+    fn quartic_with_four_real_roots() {
+        // (x - 1)(x - 2)(x - 3)(x - 4)
+        assert_roots(
+            &[1.0, 2.0, 3.0, 4.0],
+            solve_quartic(1.0, -10.0, 35.0, -50.0, 24.0),
+        );
+    }
+
+    #[test]
+    fn quartic_with_two_real_roots() {
+        // 2 (x^2 + 1)(x - 2)(x + 5)
+        assert_roots(&[-5.0, 2.0], solve_quartic(2.0, 6.0, -18.0, 6.0, -20.0));
+    }
+
+    #[test]
+    fn biquadratic_quartic() {
+        // (x^2 - 1)(x^2 - 4)
+        assert_roots(
+            &[-2.0, -1.0, 1.0, 2.0],
+            solve_quartic(1.0, 0.0, -5.0, 0.0, 4.0),
+        );
+    }
+
+    #[test]
+    fn cubic_with_three_real_roots() {
+        // 2 (x + 3)(x - 1)(x - 2)
+        assert_roots(&[-3.0, 1.0, 2.0], solve_cubic(2.0, 0.0, -14.0, 12.0));
+    }
+
+    #[test]
+    fn degenerate_quartic_is_cubic() {
+        // (x + 3)(x - 1)(x - 2), with and without a leading term lost in rounding
+        assert_roots(&[-3.0, 1.0, 2.0], solve_quartic(0.0, 1.0, 0.0, -7.0, 6.0));
+        assert_roots(&[-3.0, 1.0, 2.0], solve_quartic(1e-20, 1.0, 0.0, -7.0, 6.0));
+    }
+}
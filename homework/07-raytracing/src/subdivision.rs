@@ -0,0 +1,245 @@
+use crate::mesh::{Mesh, MeshFace, MeshQuad};
+use crate::models::Material;
+use nalgebra::{Point3, Vector2, Vector3};
+use std::collections::{HashMap, HashSet};
+
+// a face with any number of corners, given counter-clockwise
+struct Polygon {
+    indices: Vec<usize>,
+    material: Material,
+}
+
+fn edge(a: usize, b: usize) -> (usize, usize) {
+    (a.min(b), a.max(b))
+}
+
+// which faces meet at each edge and which vertices are joined to each vertex
+struct Topology {
+    edge_faces: HashMap<(usize, usize), Vec<usize>>,
+    neighbours: Vec<Vec<usize>>,
+    vertex_faces: Vec<Vec<usize>>,
+}
+
+impl Topology {
+    fn new(vertex_count: usize, polygons: &[Polygon]) -> Self {
+        let mut edge_faces: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+        let mut neighbours = vec![vec![]; vertex_count];
+        let mut vertex_faces = vec![vec![]; vertex_count];
+        for (f, polygon) in polygons.iter().enumerate() {
+            let n = polygon.indices.len();
+            for i in 0..n {
+                let (a, b) = (polygon.indices[i], polygon.indices[(i + 1) % n]);
+                let faces = edge_faces.entry(edge(a, b)).or_default();
+                if faces.is_empty() {
+                    neighbours[a].push(b);
+                    neighbours[b].push(a);
+                }
+                faces.push(f);
+                vertex_faces[a].push(f);
+            }
+        }
+        Self {
+            edge_faces,
+            neighbours,
+            vertex_faces,
+        }
+    }
+
+    // creased edges and edges on the border of the mesh are kept sharp
+    fn is_sharp(&self, creases: &HashSet<(usize, usize)>, a: usize, b: usize) -> bool {
+        let key = edge(a, b);
+        creases.contains(&key) || self.edge_faces.get(&key).map_or(0, |f| f.len()) != 2
+    }
+
+    // Moves a vertex of the old mesh, using `smooth` unless it lies on sharp edges. A vertex on
+    // a crease slides along it and one where three or more sharp edges meet stays put.
+    fn vertex_point(
+        &self,
+        vertices: &[Point3<f64>],
+        creases: &HashSet<(usize, usize)>,
+        v: usize,
+        smooth: impl Fn() -> Point3<f64>,
+    ) -> Point3<f64> {
+        let sharp: Vec<usize> = self.neighbours[v]
+            .iter()
+            .cloned()
+            .filter(|&n| self.is_sharp(creases, v, n))
+            .collect();
+        match sharp.len() {
+            _ if self.neighbours[v].is_empty() => vertices[v],
+            0 | 1 => smooth(),
+            2 => Point3::from(
+                vertices[v].coords.scale(0.75)
+                    + (vertices[sharp[0]].coords + vertices[sharp[1]].coords).scale(0.125),
+            ),
+            _ => vertices[v],
+        }
+    }
+}
+
+fn polygons(mesh: &Mesh) -> Vec<Polygon> {
+    let triangles = mesh.faces.iter().map(|f| Polygon {
+        indices: f.indices.to_vec(),
+        material: f.material,
+    });
+    let quads = mesh.quads.iter().map(|q| Polygon {
+        indices: q.indices.to_vec(),
+        material: q.material,
+    });
+    triangles.chain(quads).collect()
+}
+
+fn average(points: impl Iterator<Item = Vector3<f64>>) -> Vector3<f64> {
+    let (sum, count) = points.fold((Vector3::zeros(), 0), |(s, c), p| (s + p, c + 1));
+    sum.scale(1.0 / count as f64)
+}
+
+// One step of Loop subdivision, which splits each triangle into four and smooths the result
+// towards a surface with continuous curvature.
+pub fn loop_subdivide(mesh: &mut Mesh) {
+    let polygons = polygons(mesh);
+    let topology = Topology::new(mesh.vertices.len(), &polygons);
+    let old = mesh.vertices.clone();
+
+    let mut vertices: Vec<Point3<f64>> = (0..old.len())
+        .map(|v| {
+            topology.vertex_point(&old, &mesh.creases, v, || {
+                let n = topology.neighbours[v].len() as f64;
+                let beta = match topology.neighbours[v].len() {
+                    3 => 3.0 / 16.0,
+                    _ => 3.0 / (8.0 * n),
+                };
+                let sum: Vector3<f64> = topology.neighbours[v].iter().map(|&u| old[u].coords).sum();
+                Point3::from(old[v].coords.scale(1.0 - n * beta) + sum.scale(beta))
+            })
+        })
+        .collect();
+    let mut uvs = mesh.uvs.clone();
+
+    let mut edge_points: HashMap<(usize, usize), usize> = HashMap::new();
+    for (&(a, b), faces) in &topology.edge_faces {
+        let p = match topology.is_sharp(&mesh.creases, a, b) {
+            true => (old[a].coords + old[b].coords).scale(0.5),
+            false => {
+                // the corners of the two triangles which lie across from the edge
+                let opposite: Vector3<f64> = faces
+                    .iter()
+                    .flat_map(|&f| polygons[f].indices.iter())
+                    .filter(|&&v| v != a && v != b)
+                    .map(|&v| old[v].coords)
+                    .sum();
+                (old[a].coords + old[b].coords).scale(3.0 / 8.0) + opposite.scale(1.0 / 8.0)
+            }
+        };
+        vertices.push(Point3::from(p));
+        uvs.push((mesh.uvs[a] + mesh.uvs[b]).scale(0.5));
+        edge_points.insert((a, b), vertices.len() - 1);
+    }
+
+    mesh.faces = polygons
+        .iter()
+        .flat_map(|polygon| {
+            let [a, b, c] = [0, 1, 2].map(|i| polygon.indices[i]);
+            let [ab, bc, ca] = [(a, b), (b, c), (c, a)].map(|(i, j)| edge_points[&edge(i, j)]);
+            [[a, ab, ca], [ab, b, bc], [ca, bc, c], [ab, bc, ca]].map(|indices| MeshFace {
+                indices,
+                material: polygon.material,
+            })
+        })
+        .collect();
+    mesh.creases = split_creases(&mesh.creases, &edge_points);
+    mesh.vertices = vertices;
+    mesh.uvs = uvs;
+}
+
+// One step of Catmull-Clark subdivision, which turns every face into quads, one for each of its
+// corners, and smooths the result.
+pub fn catmull_clark(mesh: &mut Mesh) {
+    let polygons = polygons(mesh);
+    let topology = Topology::new(mesh.vertices.len(), &polygons);
+    let old = mesh.vertices.clone();
+
+    let face_points: Vec<Vector3<f64>> = polygons
+        .iter()
+        .map(|p| average(p.indices.iter().map(|&v| old[v].coords)))
+        .collect();
+
+    let mut vertices: Vec<Point3<f64>> = (0..old.len())
+        .map(|v| {
+            topology.vertex_point(&old, &mesh.creases, v, || {
+                let n = topology.neighbours[v].len() as f64;
+                let f = average(topology.vertex_faces[v].iter().map(|&f| face_points[f]));
+                let r = average(
+                    topology.neighbours[v]
+                        .iter()
+                        .map(|&u| (old[v].coords + old[u].coords).scale(0.5)),
+                );
+                Point3::from((f + r.scale(2.0) + old[v].coords.scale(n - 3.0)).scale(1.0 / n))
+            })
+        })
+        .collect();
+    let mut uvs = mesh.uvs.clone();
+
+    let mut face_indices = vec![];
+    for (polygon, point) in polygons.iter().zip(&face_points) {
+        vertices.push(Point3::from(*point));
+        let uv: Vector2<f64> = polygon.indices.iter().map(|&v| mesh.uvs[v]).sum();
+        uvs.push(uv.scale(1.0 / polygon.indices.len() as f64));
+        face_indices.push(vertices.len() - 1);
+    }
+
+    let mut edge_points: HashMap<(usize, usize), usize> = HashMap::new();
+    for (&(a, b), faces) in &topology.edge_faces {
+        let midpoint = (old[a].coords + old[b].coords).scale(0.5);
+        let p = match topology.is_sharp(&mesh.creases, a, b) {
+            true => midpoint,
+            false => {
+                let faces = average(faces.iter().map(|&f| face_points[f]));
+                (midpoint + faces).scale(0.5)
+            }
+        };
+        vertices.push(Point3::from(p));
+        uvs.push((mesh.uvs[a] + mesh.uvs[b]).scale(0.5));
+        edge_points.insert((a, b), vertices.len() - 1);
+    }
+
+    mesh.faces = vec![];
+    mesh.quads = polygons
+        .iter()
+        .zip(&face_indices)
+        .flat_map(|(polygon, &center)| {
+            let n = polygon.indices.len();
+            (0..n).map(move |i| {
+                let v = polygon.indices[i];
+                let next = polygon.indices[(i + 1) % n];
+                let previous = polygon.indices[(i + n - 1) % n];
+                (
+                    v,
+                    edge(v, next),
+                    center,
+                    edge(previous, v),
+                    polygon.material,
+                )
+            })
+        })
+        .map(|(v, next, center, previous, material)| MeshQuad {
+            indices: [v, edge_points[&next], center, edge_points[&previous]],
+            material,
+        })
+        .collect();
+    mesh.creases = split_creases(&mesh.creases, &edge_points);
+    mesh.vertices = vertices;
+    mesh.uvs = uvs;
+}
+
+// each creased edge becomes the two halves it was split into
+fn split_creases(
+    creases: &HashSet<(usize, usize)>,
+    edge_points: &HashMap<(usize, usize), usize>,
+) -> HashSet<(usize, usize)> {
+    creases
+        .iter()
+        .filter_map(|&(a, b)| edge_points.get(&(a, b)).map(|&m| [edge(a, m), edge(m, b)]))
+        .flatten()
+        .collect()
+}
@@ -0,0 +1,185 @@
+use crate::mesh::{Mesh, MeshOptions};
+use crate::models::{Material, SceneObject};
+use crate::texture::Texture;
+use crate::transform::Transform;
+use crate::utils::Rng;
+use nalgebra::{Point3, Vector2, Vector3};
+use std::f64::consts::PI;
+
+// height of the tallest peak above the lowest valley, relative to the size of the terrain
+const RELIEF: f64 = 0.2;
+// relative heights below which the terrain is flooded, or counts as grass or rock, above which
+// there is snow
+const WATER_LEVEL: f64 = 0.3;
+const GRASS_LEVEL: f64 = 0.55;
+const ROCK_LEVEL: f64 = 0.8;
+const WATER: Vector3<f64> = Vector3::new(0.1, 0.3, 0.7);
+const GRASS: Vector3<f64> = Vector3::new(0.25, 0.55, 0.2);
+const ROCK: Vector3<f64> = Vector3::new(0.45, 0.4, 0.35);
+const SNOW: Vector3<f64> = Vector3::new(0.95, 0.95, 0.95);
+const WATER_SHININESS: f64 = 0.3;
+const SMOOTH_ANGLE: f64 = 90.0;
+
+// Heights on a `resolution` by `resolution` grid, built by raising the grid on one side of a
+// random line and lowering it on the other `iterations` times. Later faults move the grid less,
+// adding detail without more large cliffs. Scaled to lie between 0 and 1.
+pub fn fault_heights(resolution: usize, iterations: usize, seed: u64) -> Vec<f64> {
+    let rng = Rng::new(seed);
+    let mut heights = vec![0.0; resolution * resolution];
+    for i in 0..iterations {
+        let p = Vector2::new(rng.next_f64(), rng.next_f64()).scale(resolution as f64);
+        let theta = rng.next_f64() * 2.0 * PI;
+        let n = Vector2::new(theta.cos(), theta.sin());
+        let size = rng.next_f64() * (1.0 - i as f64 / iterations as f64);
+        for (index, h) in heights.iter_mut().enumerate() {
+            let cell = Vector2::new((index / resolution) as f64, (index % resolution) as f64);
+            match (cell - p).dot(&n) >= 0.0 {
+                true => *h += size,
+                false => *h -= size,
+            }
+        }
+    }
+    let min = heights.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = heights.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    match max - min > 0.0 {
+        true => heights.iter().map(|h| (h - min) / (max - min)).collect(),
+        false => heights,
+    }
+}
+
+fn height_color(h: f64) -> Vector3<f64> {
+    match h {
+        h if h <= WATER_LEVEL => WATER,
+        h if h < GRASS_LEVEL => GRASS,
+        h if h < ROCK_LEVEL => ROCK,
+        _ => SNOW,
+    }
+}
+
+// Fault line terrain covering a `size` by `size` square around the origin, colored by height.
+// Everything under the water level is flattened into a lake. `textures` are those of the scene,
+// which a height map displacing the terrain is looked up in.
+pub fn terrain(
+    size: f64,
+    resolution: usize,
+    iterations: usize,
+    seed: u64,
+    material: Material,
+    transform: Transform,
+    textures: &[Texture],
+) -> Vec<SceneObject> {
+    let heights: Vec<f64> = fault_heights(resolution, iterations, seed)
+        .into_iter()
+        .map(|h| h.max(WATER_LEVEL))
+        .collect();
+    let step = 1.0 / (resolution - 1) as f64;
+    let uvs: Vec<Vector2<f64>> = (0..resolution * resolution)
+        .map(|index| Vector2::new((index / resolution) as f64, (index % resolution) as f64))
+        .map(|cell| cell.scale(step))
+        .collect();
+    let positions: Vec<Point3<f64>> = uvs
+        .iter()
+        .zip(&heights)
+        .map(|(uv, h)| {
+            Point3::new(
+                (uv.x - 0.5) * size,
+                (h - 0.5) * RELIEF * size,
+                (uv.y - 0.5) * size,
+            )
+        })
+        .collect();
+    let mut mesh = Mesh::new();
+    mesh.transform = transform;
+    let index = |i: usize, j: usize| i * resolution + j;
+    for i in 0..resolution - 1 {
+        for j in 0..resolution - 1 {
+            let faces = [
+                [index(i, j), index(i, j + 1), index(i + 1, j)],
+                [index(i + 1, j), index(i, j + 1), index(i + 1, j + 1)],
+            ];
+            for face in faces {
+                let h = face.iter().map(|&v| heights[v]).sum::<f64>() / 3.0;
+                let face_material = Material {
+                    color: height_color(h),
+                    shininess: match h <= WATER_LEVEL {
+                        true => WATER_SHININESS,
+                        false => material.shininess,
+                    },
+                    ..material
+                };
+                mesh.add_face(face, face.map(Some), &positions, &uvs, face_material);
+            }
+        }
+    }
+    let options = MeshOptions {
+        smooth: Some(SMOOTH_ANGLE),
+        weld: None,
+        subdivide: None,
+    };
+    mesh.into_objects(&options, textures)
+}
+
+#[cfg(test)]
+mod terrain_tests {
+    use super::*;
+    use crate::models::{ObjPrimative, DEFAULT_MATERIAL};
+    use crate::texture::{Displacement, DisplacementSource, WrapMode};
+
+    #[test]
+    fn fault_heights_are_normalized_and_repeatable() {
+        let heights = fault_heights(16, 50, 7);
+        assert_eq!(heights, fault_heights(16, 50, 7));
+        assert_ne!(heights, fault_heights(16, 50, 8));
+        assert!(heights.iter().all(|&h| (0.0..=1.0).contains(&h)));
+        assert!(heights.contains(&0.0));
+        assert!(heights.contains(&1.0));
+    }
+
+    #[test]
+    fn terrain_is_two_smooth_triangles_per_cell() {
+        let objects = terrain(10.0, 8, 20, 1, DEFAULT_MATERIAL, Transform::identity(), &[]);
+        assert_eq!(2 * 7 * 7, objects.len());
+        for object in objects {
+            match object.primitive {
+                ObjPrimative::Triangle {
+                    n,
+                    normals: Some(_),
+                    ..
+                } => assert!(n.y > 0.0),
+                _ => panic!("expected a smooth triangle"),
+            }
+        }
+    }
+
+    fn highest(objects: &[SceneObject]) -> f64 {
+        objects
+            .iter()
+            .flat_map(|o| match o.primitive {
+                ObjPrimative::Triangle { vertices, .. } => vertices.map(|v| v.y),
+                _ => panic!("expected a triangle"),
+            })
+            .fold(f64::NEG_INFINITY, f64::max)
+    }
+
+    #[test]
+    fn terrain_can_be_displaced_by_a_height_map() {
+        let white = Texture {
+            width: 1,
+            height: 1,
+            texels: vec![Vector3::new(1.0, 1.0, 1.0)],
+            wrap: WrapMode::Clamp,
+        };
+        let material = Material {
+            displacement: Some(Displacement {
+                source: DisplacementSource::Texture(0),
+                amount: 0.5,
+                levels: 1,
+            }),
+            ..DEFAULT_MATERIAL
+        };
+        let flat = terrain(10.0, 8, 20, 1, DEFAULT_MATERIAL, Transform::identity(), &[]);
+        let displaced = terrain(10.0, 8, 20, 1, material, Transform::identity(), &[white]);
+        assert_eq!(4 * flat.len(), displaced.len());
+        assert!(highest(&displaced) > highest(&flat) + 0.25);
+    }
+}
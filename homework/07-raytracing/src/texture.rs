@@ -0,0 +1,169 @@
+use crate::pattern::{noise, turbulence};
+use nalgebra::{Point3, Vector2, Vector3};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+}
+
+impl FromStr for WrapMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "repeat" => Ok(WrapMode::Repeat),
+            "clamp" => Ok(WrapMode::Clamp),
+            _ => Err(format!("Unknown wrap mode: {}", s)),
+        }
+    }
+}
+
+// texture which changes the shading normal of a material
+#[derive(Debug, Clone, Copy)]
+pub enum BumpMap {
+    // tangent space normals encoded as rgb
+    Normal(usize),
+    // grayscale heights, scaled by `strength`
+    Height { texture: usize, strength: f64 },
+}
+
+// where the heights a mesh is displaced by come from
+#[derive(Debug, Clone, Copy)]
+pub enum DisplacementSource {
+    // grayscale height map, looked up by texture coordinate
+    Texture(usize),
+    // noise at the vertex position, with features about `scale` units apart
+    Perlin { scale: f64 },
+    Turbulence { scale: f64 },
+}
+
+// moves the vertices of a mesh along their normals after subdividing it `levels` times
+#[derive(Debug, Clone, Copy)]
+pub struct Displacement {
+    pub source: DisplacementSource,
+    pub amount: f64,
+    pub levels: usize,
+}
+
+impl Displacement {
+    // distance to move a vertex along its normal
+    pub fn offset(&self, p: &Point3<f64>, uv: &Vector2<f64>, textures: &[Texture]) -> f64 {
+        let height = match self.source {
+            DisplacementSource::Texture(t) => textures[t].sample(uv).x,
+            DisplacementSource::Perlin { scale } => noise(&p.map(|c| c / scale)),
+            DisplacementSource::Turbulence { scale } => turbulence(&p.map(|c| c / scale)),
+        };
+        height * self.amount
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Texture {
+    pub width: usize,
+    pub height: usize,
+    // rgb texels, row by row starting from the top of the image
+    pub texels: Vec<Vector3<f64>>,
+    pub wrap: WrapMode,
+}
+
+fn srgb_to_linear(c: f64) -> f64 {
+    match c {
+        x if x <= 0.04045 => x / 12.92,
+        x => ((x + 0.055) / 1.055).powf(2.4),
+    }
+}
+
+impl Texture {
+    // Loads an image as a texture. Color images are stored in sRGB and get converted to linear
+    // values, data such as normal and height maps are used as is.
+    pub fn load(file: &str, wrap: WrapMode, srgb: bool) -> Result<Self, String> {
+        let image = match image::open(file) {
+            Ok(i) => i.into_rgb32f(),
+            Err(e) => return Err(format!("Failed to load texture {}: {}", file, e)),
+        };
+        let texels = image
+            .pixels()
+            .map(|p| Vector3::new(p[0] as f64, p[1] as f64, p[2] as f64))
+            .map(|c| match srgb {
+                true => c.map(srgb_to_linear),
+                false => c,
+            })
+            .collect();
+        Ok(Self {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            texels,
+            wrap,
+        })
+    }
+
+    fn wrap_index(&self, i: i64, size: usize) -> usize {
+        match self.wrap {
+            WrapMode::Repeat => i.rem_euclid(size as i64) as usize,
+            WrapMode::Clamp => i.clamp(0, size as i64 - 1) as usize,
+        }
+    }
+
+    fn texel(&self, x: i64, y: i64) -> Vector3<f64> {
+        let x = self.wrap_index(x, self.width);
+        let y = self.wrap_index(y, self.height);
+        self.texels[y * self.width + x]
+    }
+
+    // Bilinearly filtered lookup. `v` runs from the bottom of the image (0) to the top (1).
+    pub fn sample(&self, uv: &Vector2<f64>) -> Vector3<f64> {
+        let x = uv.x * self.width as f64 - 0.5;
+        let y = (1.0 - uv.y) * self.height as f64 - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let fx = x - x0;
+        let fy = y - y0;
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.texel(x0, y0).scale(1.0 - fx) + self.texel(x0 + 1, y0).scale(fx);
+        let bottom = self.texel(x0, y0 + 1).scale(1.0 - fx) + self.texel(x0 + 1, y0 + 1).scale(fx);
+        top.scale(1.0 - fy) + bottom.scale(fy)
+    }
+
+    // size of one texel in texture coordinates
+    pub fn texel_size(&self) -> Vector2<f64> {
+        Vector2::new(1.0 / self.width as f64, 1.0 / self.height as f64)
+    }
+}
+
+#[cfg(test)]
+mod texture_tests {
+    use super::*;
+
+    // 2x1 texture, black on the left and white on the right
+    fn texture(wrap: WrapMode) -> Texture {
+        Texture {
+            width: 2,
+            height: 1,
+            texels: vec![Vector3::zeros(), Vector3::new(1.0, 1.0, 1.0)],
+            wrap,
+        }
+    }
+
+    #[test]
+    fn sample_returns_texel_at_its_center() {
+        let t = texture(WrapMode::Clamp);
+        assert_eq!(0.0, t.sample(&Vector2::new(0.25, 0.5)).x);
+        assert_eq!(1.0, t.sample(&Vector2::new(0.75, 0.5)).x);
+    }
+
+    #[test]
+    fn sample_interpolates_between_texels() {
+        let t = texture(WrapMode::Clamp);
+        assert!((t.sample(&Vector2::new(0.5, 0.5)).x - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn sample_wraps_or_clamps_outside_the_image() {
+        let repeat = texture(WrapMode::Repeat);
+        let clamp = texture(WrapMode::Clamp);
+        assert!((repeat.sample(&Vector2::new(1.0, 0.5)).x - 0.5).abs() < 1e-9);
+        assert_eq!(1.0, clamp.sample(&Vector2::new(1.0, 0.5)).x);
+        assert_eq!(0.0, repeat.sample(&Vector2::new(1.25, 0.5)).x);
+    }
+}
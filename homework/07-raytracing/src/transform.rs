@@ -0,0 +1,142 @@
+use crate::models::AABB;
+use crate::utils::gamma;
+use nalgebra::{Matrix4, Point3, Unit, Vector3};
+
+// An affine transformation from object space into world space, along with its inverse
+#[derive(Debug, Clone, Copy)]
+pub struct Transform {
+    pub matrix: Matrix4<f64>,
+    pub inverse: Matrix4<f64>,
+}
+
+impl Transform {
+    pub fn identity() -> Self {
+        Self {
+            matrix: Matrix4::identity(),
+            inverse: Matrix4::identity(),
+        }
+    }
+
+    pub fn translation(v: Vector3<f64>) -> Self {
+        Self {
+            matrix: Matrix4::new_translation(&v),
+            inverse: Matrix4::new_translation(&-v),
+        }
+    }
+
+    // rotation by `degrees` counter-clockwise around `axis`
+    pub fn rotation(axis: Vector3<f64>, degrees: f64) -> Result<Self, String> {
+        let axis = match Unit::try_new(axis, f64::EPSILON) {
+            Some(a) => a,
+            None => return Err("Cannot rotate around a zero length axis".to_string()),
+        };
+        let matrix = Matrix4::from_axis_angle(&axis, degrees.to_radians());
+        Ok(Self {
+            matrix,
+            inverse: matrix.transpose(),
+        })
+    }
+
+    pub fn scaling(v: Vector3<f64>) -> Result<Self, String> {
+        if v.iter().any(|&c| c == 0.0) {
+            return Err("Cannot scale by zero".to_string());
+        }
+        Ok(Self {
+            matrix: Matrix4::new_nonuniform_scaling(&v),
+            inverse: Matrix4::new_nonuniform_scaling(&v.map(|c| 1.0 / c)),
+        })
+    }
+
+    // the transform which applies `other` first and then this one
+    pub fn then(&self, other: &Transform) -> Self {
+        Self {
+            matrix: self.matrix * other.matrix,
+            inverse: other.inverse * self.inverse,
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.matrix == Matrix4::identity()
+    }
+
+    pub fn point(&self, p: &Point3<f64>) -> Point3<f64> {
+        self.matrix.transform_point(p)
+    }
+
+    pub fn vector(&self, v: &Vector3<f64>) -> Vector3<f64> {
+        self.matrix.transform_vector(v)
+    }
+
+    // normals transform by the inverse transpose so that they stay perpendicular to the surface
+    pub fn normal(&self, n: &Vector3<f64>) -> Vector3<f64> {
+        self.inverse.transpose().transform_vector(n).normalize()
+    }
+
+    // bound on the error of `point(p)` when `p` is off by up to `error` in each axis, including
+    // the rounding of the transform itself
+    pub fn point_error(&self, p: &Point3<f64>, error: &Vector3<f64>) -> Vector3<f64> {
+        let m = self.matrix.fixed_view::<3, 3>(0, 0).abs();
+        let translation = self.matrix.fixed_view::<3, 1>(0, 3).abs();
+        m * error + (m * p.coords.abs() + translation).scale(gamma(3.0))
+    }
+
+    pub fn inverse_point(&self, p: &Point3<f64>) -> Point3<f64> {
+        self.inverse.transform_point(p)
+    }
+
+    pub fn inverse_vector(&self, v: &Vector3<f64>) -> Vector3<f64> {
+        self.inverse.transform_vector(v)
+    }
+
+    // box around all eight transformed corners of `aabb`
+    pub fn aabb(&self, aabb: &AABB) -> AABB {
+        let mut min = Point3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY);
+        let mut max = Point3::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY);
+        for i in 0..8 {
+            let corner = Point3::new(
+                if i & 1 == 0 { aabb.min.x } else { aabb.max.x },
+                if i & 2 == 0 { aabb.min.y } else { aabb.max.y },
+                if i & 4 == 0 { aabb.min.z } else { aabb.max.z },
+            );
+            let p = self.point(&corner);
+            min = min.inf(&p);
+            max = max.sup(&p);
+        }
+        AABB::new(min, max)
+    }
+}
+
+#[cfg(test)]
+mod transform_tests {
+    use super::*;
+
+    fn assert_close(a: Vector3<f64>, b: Vector3<f64>) {
+        assert!((a - b).magnitude() < 1e-9, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn then_applies_the_inner_transform_first() {
+        let t = Transform::translation(Vector3::new(1.0, 0.0, 0.0))
+            .then(&Transform::scaling(Vector3::new(2.0, 2.0, 2.0)).unwrap());
+        let p = t.point(&Point3::new(1.0, 1.0, 1.0));
+        assert_close(p.coords, Vector3::new(3.0, 2.0, 2.0));
+        assert_close(t.inverse_point(&p).coords, Vector3::new(1.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn rotation_is_counter_clockwise() {
+        let t = Transform::rotation(Vector3::new(0.0, 0.0, 1.0), 90.0).unwrap();
+        assert_close(
+            t.vector(&Vector3::new(1.0, 0.0, 0.0)),
+            Vector3::new(0.0, 1.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn normals_stay_perpendicular_under_non_uniform_scaling() {
+        let t = Transform::scaling(Vector3::new(4.0, 1.0, 1.0)).unwrap();
+        let tangent = t.vector(&Vector3::new(1.0, -1.0, 0.0));
+        let n = t.normal(&Vector3::new(1.0, 1.0, 0.0));
+        assert!(tangent.dot(&n).abs() < 1e-9);
+    }
+}
@@ -1,5 +1,6 @@
 use image::Rgba;
 use nalgebra::{Vector3, Vector4};
+use std::cell::Cell;
 
 pub const BLACK: Vector3<f64> = Vector3::new(0.0, 0.0, 0.0);
 
@@ -11,6 +12,56 @@ pub fn clamp(t: f64) -> f64 {
     }
 }
 
+// Small xorshift generator. Keeps its state in a cell so that it can be shared by the
+// renderer's `&self` methods.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: Cell<u64>,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift only ever returns zero from a state of zero, which one seed would give
+        let state = match seed ^ 0x9E37_79B9_7F4A_7C15 {
+            0 => 0x9E37_79B9_7F4A_7C15,
+            s => s,
+        };
+        Self {
+            state: Cell::new(state),
+        }
+    }
+
+    pub fn next_u64(&self) -> u64 {
+        let mut x = self.state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state.set(x);
+        x
+    }
+
+    // uniform in [0, 1)
+    pub fn next_f64(&self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// bound on the relative rounding error of `n` floating point operations in a row
+pub fn gamma(n: f64) -> f64 {
+    n * f64::EPSILON / (1.0 - n * f64::EPSILON)
+}
+
+// returns two unit vectors perpendicular to `n` and to each other
+pub fn orthonormal_basis(n: &Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+    let helper = match n.x.abs() > 0.9 {
+        true => Vector3::new(0.0, 1.0, 0.0),
+        false => Vector3::new(1.0, 0.0, 0.0),
+    };
+    let t = helper.cross(n).normalize();
+    let b = n.cross(&t);
+    (t, b)
+}
+
 pub fn vec4_to_rgb(v: Vector4<f64>, exposure: Option<f64>) -> Rgba<u8> {
     let rgb = Vector3::<f32>::new(v[0] as f32, v[1] as f32, v[2] as f32)
         .map(|c| match exposure {
@@ -56,3 +107,17 @@ pub fn vec3_add_alpha(a: &Option<Vector3<f64>>) -> Vector4<f64> {
         Some(a) => Vector4::new(a.x, a.y, a.z, 1.0),
     }
 }
+
+#[cfg(test)]
+mod rng_tests {
+    use super::*;
+
+    #[test]
+    fn every_seed_gives_a_changing_sequence() {
+        for seed in [0, 1, 0x9E37_79B9_7F4A_7C15] {
+            let rng = Rng::new(seed);
+            let first = rng.next_u64();
+            assert!((0..10).any(|_| rng.next_u64() != first));
+        }
+    }
+}
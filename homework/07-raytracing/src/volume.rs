@@ -0,0 +1,283 @@
+use crate::intersections::box_crossings;
+use crate::raytracer::Ray;
+use crate::transform::Transform;
+use crate::utils::Rng;
+use nalgebra::{Point3, Vector3};
+use std::fs;
+use std::ops::Sub;
+
+// Dense grid of densities filling a box, such as smoke from a simulation or a ct scan. Values
+// are stored with x changing fastest, then y, then z, and lie at the centers of their voxels.
+#[derive(Debug, Clone)]
+pub struct Volume {
+    pub resolution: [usize; 3],
+    densities: Vec<f64>,
+    pub min: Point3<f64>,
+    pub max: Point3<f64>,
+    // densest point of the grid, which bounds the steps taken by tracking
+    pub majorant: f64,
+    // part of the light taken out of a ray which is scattered rather than absorbed
+    pub color: Vector3<f64>,
+    // places the box in the scene
+    pub transform: Option<Transform>,
+}
+
+// A text grid is the resolution followed by every density, separated by whitespace.
+fn parse_text(text: &str) -> Result<([usize; 3], Vec<f64>), String> {
+    let mut values = text.split_whitespace();
+    let mut resolution = [0; 3];
+    for r in resolution.iter_mut() {
+        *r = match values.next().map(|v| v.parse::<usize>()) {
+            Some(Ok(r)) => r,
+            Some(Err(e)) => return Err(e.to_string()),
+            None => return Err("Volume is missing its resolution".to_string()),
+        };
+    }
+    let densities = match values.map(|v| v.parse::<f64>()).collect() {
+        Ok(d) => d,
+        Err(e) => return Err(format!("Failed to read volume density: {}", e)),
+    };
+    Ok((resolution, densities))
+}
+
+// A raw grid is the resolution as three little endian u32s followed by every density as a
+// little endian f32.
+fn parse_raw(bytes: &[u8]) -> Result<([usize; 3], Vec<f64>), String> {
+    if bytes.len() < 12 || !bytes.len().is_multiple_of(4) {
+        return Err("Raw volume is not a whole number of 4 byte values".to_string());
+    }
+    let words: Vec<[u8; 4]> = bytes
+        .chunks_exact(4)
+        .map(|c| [c[0], c[1], c[2], c[3]])
+        .collect();
+    let resolution = [0, 1, 2].map(|i| u32::from_le_bytes(words[i]) as usize);
+    let densities = words[3..]
+        .iter()
+        .map(|&w| f32::from_le_bytes(w) as f64)
+        .collect();
+    Ok((resolution, densities))
+}
+
+impl Volume {
+    // loads a raw grid from files ending in .raw and a text grid from anything else
+    pub fn load(file: &str) -> Result<([usize; 3], Vec<f64>), String> {
+        let grid = match file.ends_with(".raw") {
+            true => match fs::read(file) {
+                Ok(bytes) => parse_raw(&bytes),
+                Err(e) => return Err(format!("Failed to load volume {}: {}", file, e)),
+            },
+            false => match fs::read_to_string(file) {
+                Ok(text) => parse_text(&text),
+                Err(e) => return Err(format!("Failed to load volume {}: {}", file, e)),
+            },
+        };
+        grid.map_err(|e| format!("{} in {}", e, file))
+    }
+
+    pub fn new(
+        resolution: [usize; 3],
+        densities: Vec<f64>,
+        (min, max): (Point3<f64>, Point3<f64>),
+        color: Vector3<f64>,
+        transform: Transform,
+    ) -> Result<Self, String> {
+        let voxels: usize = resolution.iter().product();
+        if voxels == 0 || densities.len() != voxels {
+            return Err(format!(
+                "Volume of {}x{}x{} voxels has {} densities",
+                resolution[0],
+                resolution[1],
+                resolution[2],
+                densities.len()
+            ));
+        }
+        if densities.iter().any(|&d| d < 0.0 || !d.is_finite()) {
+            return Err("Volume densities must be finite and not negative".to_string());
+        }
+        let majorant = densities.iter().cloned().fold(0.0, f64::max);
+        Ok(Self {
+            resolution,
+            densities,
+            min,
+            max,
+            majorant,
+            color,
+            transform: match transform.is_identity() {
+                true => None,
+                false => Some(transform),
+            },
+        })
+    }
+
+    fn voxel(&self, [x, y, z]: [usize; 3]) -> f64 {
+        let [nx, ny, _] = self.resolution;
+        self.densities[x + nx * (y + ny * z)]
+    }
+
+    // density at a point in the scene, interpolated between the nearest voxels
+    pub fn density(&self, p: &Point3<f64>) -> f64 {
+        let p = match self.transform {
+            Some(t) => t.inverse_point(p),
+            None => *p,
+        };
+        let size = self.max.sub(self.min);
+        let mut low = [0; 3];
+        let mut high = [0; 3];
+        let mut f = [0.0; 3];
+        for i in 0..3 {
+            let u = (p[i] - self.min[i]) / size[i];
+            if !(0.0..=1.0).contains(&u) {
+                return 0.0;
+            }
+            let x =
+                (u * self.resolution[i] as f64 - 0.5).clamp(0.0, (self.resolution[i] - 1) as f64);
+            low[i] = x.floor() as usize;
+            high[i] = (low[i] + 1).min(self.resolution[i] - 1);
+            f[i] = x - low[i] as f64;
+        }
+        let mut density = 0.0;
+        for corner in 0..8 {
+            let mut index = [0; 3];
+            let mut weight = 1.0;
+            for i in 0..3 {
+                match corner >> i & 1 {
+                    0 => {
+                        index[i] = low[i];
+                        weight *= 1.0 - f[i];
+                    }
+                    _ => {
+                        index[i] = high[i];
+                        weight *= f[i];
+                    }
+                }
+            }
+            density += weight * self.voxel(index);
+        }
+        density
+    }
+
+    // how far along the ray it leaves the box, which bounds tracking even if `end` does not
+    fn exit(&self, ray: &Ray) -> f64 {
+        let local = match self.transform {
+            Some(t) => Ray::new(
+                t.inverse_point(&ray.origin),
+                t.inverse_vector(&ray.direction),
+            ),
+            None => Ray::new(ray.origin, ray.direction),
+        };
+        match box_crossings(&local, self.min, self.max).last() {
+            Some(c) => c.t,
+            None => 0.0,
+        }
+    }
+
+    // Tentative collisions along a unit length ray between `start` and `end`, spaced as if the
+    // whole volume had the majorant density. Each is a real collision with the chance of its
+    // density over the majorant, which makes up for the density being lower.
+    fn tentative_collisions<'a>(
+        &'a self,
+        ray: &'a Ray,
+        start: f64,
+        end: f64,
+        rng: &'a Rng,
+    ) -> impl Iterator<Item = (f64, f64)> + 'a {
+        let end = end.min(self.exit(ray));
+        let majorant = self.majorant;
+        let mut t = start;
+        std::iter::from_fn(move || {
+            if majorant <= 0.0 {
+                return None;
+            }
+            t -= (1.0 - rng.next_f64()).ln() / majorant;
+            match t < end {
+                true => Some((t, self.density(&(ray.origin + ray.direction.scale(t))))),
+                false => None,
+            }
+        })
+    }
+
+    // Delta tracking: the distance at which the ray is scattered or absorbed, if it is before
+    // `end`
+    pub fn sample_collision(&self, ray: &Ray, start: f64, end: f64, rng: &Rng) -> Option<f64> {
+        self.tentative_collisions(ray, start, end, rng)
+            .find(|&(_, density)| rng.next_f64() * self.majorant < density)
+            .map(|(t, _)| t)
+    }
+
+    // Ratio tracking: an estimate of the fraction of light which makes it from `start` to `end`
+    pub fn transmittance(&self, ray: &Ray, start: f64, end: f64, rng: &Rng) -> f64 {
+        self.tentative_collisions(ray, start, end, rng)
+            .map(|(_, density)| 1.0 - density / self.majorant)
+            .product()
+    }
+}
+
+#[cfg(test)]
+mod volume_tests {
+    use super::*;
+
+    fn volume(resolution: [usize; 3], densities: Vec<f64>) -> Volume {
+        let bounds = (Point3::origin(), Point3::new(1.0, 1.0, 1.0));
+        Volume::new(
+            resolution,
+            densities,
+            bounds,
+            Vector3::new(1.0, 1.0, 1.0),
+            Transform::identity(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn reads_text_and_raw_grids() {
+        let (resolution, densities) = parse_text("2 1 1\n0.5 1.5").unwrap();
+        assert_eq!([2, 1, 1], resolution);
+        assert_eq!(vec![0.5, 1.5], densities);
+        let bytes: Vec<u8> = [2u32, 1, 1]
+            .iter()
+            .flat_map(|r| r.to_le_bytes())
+            .chain([0.5f32, 1.5].iter().flat_map(|d| d.to_le_bytes()))
+            .collect();
+        assert_eq!((resolution, densities), parse_raw(&bytes).unwrap());
+    }
+
+    #[test]
+    fn rejects_grids_of_the_wrong_size() {
+        let bounds = (Point3::origin(), Point3::new(1.0, 1.0, 1.0));
+        let color = Vector3::zeros();
+        assert!(Volume::new(
+            [2, 2, 2],
+            vec![1.0; 7],
+            bounds,
+            color,
+            Transform::identity()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn density_is_interpolated_between_voxel_centers() {
+        let v = volume([2, 1, 1], vec![0.0, 2.0]);
+        assert_eq!(0.0, v.density(&Point3::new(0.25, 0.5, 0.5)));
+        assert_eq!(1.0, v.density(&Point3::new(0.5, 0.5, 0.5)));
+        assert_eq!(2.0, v.density(&Point3::new(0.9, 0.5, 0.5)));
+        assert_eq!(0.0, v.density(&Point3::new(1.5, 0.5, 0.5)));
+    }
+
+    #[test]
+    fn tracking_matches_beer_lambert_in_a_constant_volume() {
+        let v = volume([1, 1, 1], vec![2.0]);
+        let ray = Ray::new(Point3::new(-1.0, 0.5, 0.5), Vector3::new(1.0, 0.0, 0.0));
+        let rng = Rng::new(7);
+        let runs = 20000;
+        let expected = (-2.0f64).exp();
+        let ratio: f64 = (0..runs)
+            .map(|_| v.transmittance(&ray, 1.0, 5.0, &rng))
+            .sum();
+        assert!((ratio / runs as f64 - expected).abs() < 0.01);
+        let passed = (0..runs)
+            .filter(|_| v.sample_collision(&ray, 1.0, 5.0, &rng).is_none())
+            .count();
+        assert!((passed as f64 / runs as f64 - expected).abs() < 0.01);
+    }
+}